@@ -0,0 +1,303 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Who may issue which command.
+//!
+//! Actions without a rule are allowed to everyone, except `override` which is allowed to no
+//! one. A rule allows listed users, members of listed groups (defined in the policy's `groups`
+//! or known to the `Directory`) and users with at least the given permission on the
+//! repository.
+//!
+//! ```json
+//! {
+//!     "vote": { "permission": "write" },
+//!     "override": { "permission": "admin" },
+//!     "labels": { "qa-passed": { "groups": ["qa"] }, "*": { "permission": "read" } }
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::command::label_scope;
+
+/// Something a user asks gatekeeper to do.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Action<'a> {
+    Vote,
+    Label(&'a str),
+    Rfc,
+    /// Placing a hold or lifting the own one.
+    Hold,
+    Merge,
+    /// Overruling others, e.g. lifting their holds.
+    Override,
+}
+
+impl<'a> fmt::Display for Action<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Vote => write!(f, "vote"),
+            Action::Label(label) => write!(f, "change label `{}`", label),
+            Action::Rfc => write!(f, "request feedback"),
+            Action::Hold => write!(f, "place or lift holds"),
+            Action::Merge => write!(f, "merge"),
+            Action::Override => write!(f, "override others"),
+        }
+    }
+}
+
+/// Repository permission levels, ordered from the weakest.
+#[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+/// Source of group memberships and repository permissions.
+pub trait Directory {
+    /// Whether `user` is a member of `group`.
+    fn is_member(&self, user: &str, group: &str) -> bool;
+    /// Whether `user` has at least `permission` on `repo` (`owner/slug`).
+    fn can(&self, user: &str, permission: Permission, repo: &str) -> bool;
+    /// Members of `group`, `None` if there is no such group.
+    fn members(&self, group: &str) -> Option<Vec<String>>;
+}
+
+/// Directory that knows nothing; only rules listing users or config groups can allow.
+pub struct NoDirectory;
+
+impl Directory for NoDirectory {
+    fn is_member(&self, _user: &str, _group: &str) -> bool {
+        false
+    }
+
+    fn can(&self, _user: &str, _permission: Permission, _repo: &str) -> bool {
+        false
+    }
+
+    fn members(&self, _group: &str) -> Option<Vec<String>> {
+        None
+    }
+}
+
+/// Groups defined in the policy, taking precedence over groups of `directory`.
+pub struct ConfigGroups<'a> {
+    pub groups: &'a HashMap<String, Vec<String>>,
+    pub directory: &'a dyn Directory,
+}
+
+impl<'a> Directory for ConfigGroups<'a> {
+    fn is_member(&self, user: &str, group: &str) -> bool {
+        match self.groups.get(group) {
+            Some(members) => members.iter().any(|member| member == user),
+            None => self.directory.is_member(user, group),
+        }
+    }
+
+    fn can(&self, user: &str, permission: Permission, repo: &str) -> bool {
+        self.directory.can(user, permission, repo)
+    }
+
+    fn members(&self, group: &str) -> Option<Vec<String>> {
+        self.groups
+            .get(group)
+            .cloned()
+            .or_else(|| self.directory.members(group))
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Acl {
+    pub vote: Option<Rule>,
+    pub rfc: Option<Rule>,
+    pub hold: Option<Rule>,
+    /// Rules per label name, `key:*` applies to values of a scoped label and `*` to labels
+    /// without their own rule.
+    pub labels: HashMap<String, Rule>,
+    pub merge: Option<Rule>,
+    #[serde(rename = "override")]
+    pub override_: Option<Rule>,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    pub permission: Option<Permission>,
+}
+
+impl Acl {
+    pub fn allows(
+        &self,
+        user: &str,
+        action: Action,
+        repo: &str,
+        directory: &dyn Directory,
+    ) -> bool {
+        match self.rule(action) {
+            Some(rule) => rule.allows(user, repo, directory),
+            None => action != Action::Override,
+        }
+    }
+
+    fn rule(&self, action: Action) -> Option<&Rule> {
+        match action {
+            Action::Vote => self.vote.as_ref(),
+            Action::Label(label) => self
+                .labels
+                .get(label)
+                .or_else(|| {
+                    label_scope(label).and_then(|scope| self.labels.get(&format!("{}:*", scope)))
+                })
+                .or_else(|| self.labels.get("*")),
+            Action::Rfc => self.rfc.as_ref(),
+            Action::Hold => self.hold.as_ref(),
+            Action::Merge => self.merge.as_ref(),
+            Action::Override => self.override_.as_ref(),
+        }
+    }
+}
+
+impl Rule {
+    fn allows(&self, user: &str, repo: &str, directory: &dyn Directory) -> bool {
+        self.users.iter().any(|allowed| allowed == user)
+            || self
+                .groups
+                .iter()
+                .any(|group| directory.is_member(user, group))
+            || self
+                .permission
+                .is_some_and(|permission| directory.can(user, permission, repo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `qa` has alice as member, bob has write access to `o/r`, admin has admin access.
+    struct StubDirectory;
+
+    impl Directory for StubDirectory {
+        fn is_member(&self, user: &str, group: &str) -> bool {
+            group == "qa" && user == "alice"
+        }
+
+        fn can(&self, user: &str, permission: Permission, repo: &str) -> bool {
+            let granted = match user {
+                "bob" => Permission::Write,
+                "admin" => Permission::Admin,
+                _ => return false,
+            };
+            repo == "o/r" && permission <= granted
+        }
+
+        fn members(&self, group: &str) -> Option<Vec<String>> {
+            if group == "qa" {
+                Some(vec!["alice".to_string()])
+            } else {
+                None
+            }
+        }
+    }
+
+    fn acl(json: serde_json::Value) -> Acl {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn everything_but_override_is_allowed_without_rules() {
+        let acl = Acl::default();
+        for action in &[
+            Action::Vote,
+            Action::Label("wip"),
+            Action::Rfc,
+            Action::Hold,
+            Action::Merge,
+        ] {
+            assert!(acl.allows("carol", *action, "o/r", &NoDirectory));
+        }
+        assert!(!acl.allows("admin", Action::Override, "o/r", &StubDirectory));
+    }
+
+    #[test]
+    fn rules_allow_users_groups_and_permissions() {
+        let rule: Rule = serde_json::from_value(serde_json::json!({
+            "users": ["carol"],
+            "groups": ["qa"],
+            "permission": "admin",
+        }))
+        .unwrap();
+        assert!(rule.allows("carol", "o/r", &StubDirectory));
+        assert!(rule.allows("alice", "o/r", &StubDirectory));
+        assert!(rule.allows("admin", "o/r", &StubDirectory));
+        assert!(!rule.allows("admin", "o/other", &StubDirectory));
+        assert!(!rule.allows("bob", "o/r", &StubDirectory));
+    }
+
+    #[test]
+    fn permissions_include_weaker_ones() {
+        let acl = acl(serde_json::json!({ "vote": { "permission": "write" } }));
+        assert!(acl.allows("bob", Action::Vote, "o/r", &StubDirectory));
+        assert!(acl.allows("admin", Action::Vote, "o/r", &StubDirectory));
+        assert!(!acl.allows("alice", Action::Vote, "o/r", &StubDirectory));
+    }
+
+    #[test]
+    fn label_rules_fall_back_to_scope_and_wildcard() {
+        let acl = acl(serde_json::json!({
+            "labels": {
+                "priority:high": { "users": ["carol"] },
+                "priority:*": { "users": ["bob"] },
+                "*": { "users": ["alice"] },
+            }
+        }));
+        let allowed = |user, label| acl.allows(user, Action::Label(label), "o/r", &NoDirectory);
+        assert!(allowed("carol", "priority:high"));
+        assert!(!allowed("bob", "priority:high"));
+        assert!(allowed("bob", "priority:low"));
+        assert!(!allowed("alice", "priority:low"));
+        assert!(allowed("alice", "wip"));
+        assert!(allowed("alice", "status:done"));
+        assert!(!allowed("bob", "status:done"));
+    }
+
+    #[test]
+    fn config_groups_take_precedence() {
+        let groups = vec![("qa".to_string(), vec!["carol".to_string()])]
+            .into_iter()
+            .collect();
+        let directory = ConfigGroups {
+            groups: &groups,
+            directory: &StubDirectory,
+        };
+        assert!(directory.is_member("carol", "qa"));
+        assert!(!directory.is_member("alice", "qa"));
+        assert_eq!(directory.members("qa"), Some(vec!["carol".to_string()]));
+        assert!(directory.can("bob", Permission::Write, "o/r"));
+
+        let acl = acl(serde_json::json!({ "rfc": { "groups": ["qa"] } }));
+        assert!(acl.allows("carol", Action::Rfc, "o/r", &directory));
+        assert!(!acl.allows("alice", Action::Rfc, "o/r", &directory));
+    }
+
+    #[test]
+    fn unknown_groups_are_looked_up_in_the_directory() {
+        let groups = HashMap::new();
+        let directory = ConfigGroups {
+            groups: &groups,
+            directory: &StubDirectory,
+        };
+        assert!(directory.is_member("alice", "qa"));
+        assert_eq!(directory.members("qa"), Some(vec!["alice".to_string()]));
+        assert_eq!(directory.members("devs"), None);
+    }
+}
@@ -4,12 +4,15 @@
 
 use std::fmt::Debug;
 
+use chrono::{DateTime, Duration, Utc};
 use failure::Error;
 use reqwest;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json;
 use slog::{debug, o, trace};
 
+use crate::acl::Permission;
+
 pub struct BitBucketApiBasicAuth {
     client: reqwest::Client,
     username: String,
@@ -35,6 +38,31 @@ impl BitBucketApiBasicAuth {
             .header(reqwest::header::CONTENT_TYPE, "application/json")
             .send()
     }
+    pub fn post_json<T: Serialize>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.client
+            .post(url)
+            .basic_auth(self.username.clone(), Some(self.password.clone()))
+            .json(body)
+            .send()
+    }
+    pub fn put_json<T: Serialize>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.client
+            .put(url)
+            .basic_auth(self.username.clone(), Some(self.password.clone()))
+            .json(body)
+            .send()
+    }
+    pub fn username(&self) -> &str {
+        &self.username
+    }
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
@@ -96,13 +124,280 @@ where
     first_page.values_of_following_pages(&client, &logger)
 }
 
+/// Fetches a single, non-paginated, JSON value.
+pub fn value_from<T>(
+    url: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned + Debug,
+{
+    let logger = logger.new(o!(
+        "url" => url.to_string(),
+    ));
+
+    trace!(logger, "Obtaining value");
+    let mut response = client.get_json(url)?.error_for_status()?;
+    let response = response.text()?;
+    trace!(logger, "Response text: {}", response);
+    Ok(serde_json::from_str(response.as_str())?)
+}
+
+pub fn repository_user_permissions(
+    workspace: &str,
+    repo_slug: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<RepositoryUserPermission>, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/repositories/{}/{}/permissions-config/users",
+        workspace, repo_slug
+    );
+    values_from_all_pages(&url, client, logger)
+}
+
+pub fn repository_group_permissions(
+    workspace: &str,
+    repo_slug: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<RepositoryGroupPermission>, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/repositories/{}/{}/permissions-config/groups",
+        workspace, repo_slug
+    );
+    values_from_all_pages(&url, client, logger)
+}
+
+pub fn workspace_memberships(
+    workspace: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<WorkspaceMembership>, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/workspaces/{}/permissions",
+        workspace
+    );
+    values_from_all_pages(&url, client, logger)
+}
+
+/// Groups of a workspace with their members; BitBucket offers this only in the 1.0 API.
+pub fn workspace_groups(
+    workspace: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<GroupMembers>, Error> {
+    let url = format!("https://api.bitbucket.org/1.0/groups/{}", workspace);
+    value_from(&url, client, logger)
+}
+
+/// Unified diff of `spec` (`rev..rev`) in `repository` (`owner/slug`), the changes of the
+/// first revision since its common ancestor with the second.
+pub fn diff(
+    repository: &str,
+    spec: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<String, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/repositories/{}/diff/{}",
+        repository, spec
+    );
+    debug!(logger, "Obtaining diff {}", url);
+    let mut response = client.get_json(&url)?.error_for_status()?;
+    Ok(response.text()?)
+}
+
+/// Files changed by `spec` (`rev..rev`) in `repository`, with the same semantics as `diff`.
+pub fn diffstat(
+    repository: &str,
+    spec: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<DiffStat>, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/repositories/{}/diffstat/{}",
+        repository, spec
+    );
+    values_from_all_pages(&url, client, logger)
+}
+
+/// Commits reachable from `include` but not from `exclude`, newest first.
+pub fn commits_between(
+    repository: &str,
+    include: &str,
+    exclude: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Vec<Commit>, Error> {
+    let url = format!(
+        "https://api.bitbucket.org/2.0/repositories/{}/commits/{}?exclude={}",
+        repository, include, exclude
+    );
+    values_from_all_pages(&url, client, logger)
+}
+
+/// Posts a comment on the PR at `pr_api_url`, returns the created comment.
+pub fn post_comment(
+    pr_api_url: &str,
+    comment: &NewComment,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<Comment, Error> {
+    let url = format!("{}/comments", pr_api_url);
+    debug!(logger, "Posting comment to {}", url);
+    trace!(logger, "Comment: {:?}", comment);
+    let mut response = client.post_json(&url, comment)?.error_for_status()?;
+    let response = response.text()?;
+    trace!(logger, "Response text: {}", response);
+    Ok(serde_json::from_str(response.as_str())?)
+}
+
+/// Replaces the content of the comment `comment_id` on the PR at `pr_api_url`.
+pub fn update_comment(
+    pr_api_url: &str,
+    comment_id: u32,
+    raw: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<(), Error> {
+    let url = format!("{}/comments/{}", pr_api_url, comment_id);
+    debug!(logger, "Updating comment {}", url);
+    let body = serde_json::json!({ "content": { "raw": raw } });
+    let mut response = client.put_json(&url, &body)?.error_for_status()?;
+    trace!(logger, "Response text: {}", response.text()?);
+    Ok(())
+}
+
+/// Changes the title of the PR at `pr_api_url`.
+pub fn update_title(
+    pr_api_url: &str,
+    title: &str,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) -> Result<(), Error> {
+    debug!(logger, "Updating title of {} to {:?}", pr_api_url, title);
+    let body = serde_json::json!({ "title": title });
+    let mut response = client.put_json(pr_api_url, &body)?.error_for_status()?;
+    trace!(logger, "Response text: {}", response.text()?);
+    Ok(())
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone)]
+pub struct NewComment {
+    pub content: Content,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<CommentParent>,
+}
+
+impl NewComment {
+    pub fn new(raw: String) -> NewComment {
+        NewComment {
+            content: Content { raw },
+            parent: None,
+        }
+    }
+
+    pub fn reply(parent_id: u32, raw: String) -> NewComment {
+        NewComment {
+            content: Content { raw },
+            parent: Some(CommentParent { id: parent_id }),
+        }
+    }
+}
+
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct PullRequest {
     pub id: u32,
     pub title: String,
     pub state: String,
+    #[serde(default)]
+    pub draft: bool,
     pub links: PullRequestLinks,
     pub author: PullRequestUser,
+    pub source: PullRequestEndpoint,
+    pub destination: PullRequestDestination,
+    pub created_on: DateTime<Utc>,
+    pub updated_on: DateTime<Utc>,
+}
+
+/// Source of a pull request; its repository is gone if the fork was deleted.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct PullRequestEndpoint {
+    pub branch: Branch,
+    pub commit: Option<Commit>,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct PullRequestDestination {
+    pub branch: Branch,
+    pub commit: Option<Commit>,
+    pub repository: Repository,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Repository {
+    pub full_name: String,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct RepositoryUserPermission {
+    pub permission: Permission,
+    pub user: User,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct RepositoryGroupPermission {
+    pub permission: Permission,
+    pub group: Group,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Group {
+    pub slug: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct GroupMembers {
+    pub slug: String,
+    pub name: String,
+    pub members: Vec<User>,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct WorkspaceMembership {
+    pub permission: WorkspacePermission,
+    pub user: User,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspacePermission {
+    Owner,
+    Collaborator,
+    Member,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct DiffStat {
+    pub lines_added: u32,
+    pub lines_removed: u32,
+    /// `None` for added files.
+    pub old: Option<CommitFile>,
+    /// `None` for removed files.
+    pub new: Option<CommitFile>,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct CommitFile {
+    pub path: String,
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Branch {
+    pub name: String,
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
@@ -139,20 +434,58 @@ pub struct Comment {
     pub parent: Option<CommentParent>,
     pub content: Content,
     pub user: User,
+    pub created_on: DateTime<Utc>,
+    pub updated_on: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub deleted: bool,
+    /// Anchor in the diff, `None` for general comments.
+    pub inline: Option<Inline>,
+    /// Set once the thread of the comment is resolved.
+    pub resolution: Option<Resolution>,
 }
 
 impl Comment {
     pub fn is_top_level(&self) -> bool {
         self.parent == None
     }
+
+    /// Time of the last edit, `None` if the comment was not edited.
+    ///
+    /// BitBucket sets `updated_on` of fresh comments too, so updates within a second from
+    /// posting do not count as edits.
+    pub fn edited_on(&self) -> Option<DateTime<Utc>> {
+        self.updated_on
+            .filter(|updated_on| *updated_on - self.created_on > Duration::seconds(1))
+    }
 }
 
-#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
 pub struct CommentParent {
     pub id: u32,
 }
 
+/// Lines a comment is attached to: `from` in the old version of `path`, `to` in the new one.
 #[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Inline {
+    pub path: String,
+    pub from: Option<u32>,
+    pub to: Option<u32>,
+}
+
+impl Inline {
+    /// Line in the new version of the file, in the old one for removed lines.
+    pub fn line(&self) -> Option<u32> {
+        self.to.or(self.from)
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+pub struct Resolution {
+    pub user: User,
+    pub created_on: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug, Clone)]
 pub struct Content {
     pub raw: String,
 }
@@ -160,6 +493,7 @@ pub struct Content {
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct Approval {
     pub user: User,
+    pub date: DateTime<Utc>,
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
@@ -170,6 +504,7 @@ pub struct User {
 #[derive(Deserialize, PartialEq, Debug, Clone)]
 pub struct Update {
     pub source: Source,
+    pub date: DateTime<Utc>,
 }
 
 #[derive(Deserialize, PartialEq, Debug, Clone)]
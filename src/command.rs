@@ -0,0 +1,805 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Parser of the `!g` command language.
+//!
+//! ```text
+//! line    := trigger (WS command)*
+//! trigger := PREFIX | "@" MENTION (":" | ",")?
+//! command := vote | label | "rfc" WS targets | "will_revote" | "hold" (WS reason)?
+//!          | "unhold" (WS ("all" | target))? | "retract" (WS ("vote" | "rfc"))?
+//! targets := target ("," WS? target)*
+//! target  := "@"? NAME
+//! vote    := ("+" | "-")? DIGIT+ (WS "@" HEXDIGIT{4,40})? (WS "because" WS rationale)?
+//! label   := ("+" | "-") NAME (":" VALUE)?
+//! NAME    := ALPHA (ALNUM | "-" | "_" | "." | "/")*
+//! VALUE   := ALNUM (ALNUM | "-" | "_" | "." | "/")*
+//! ```
+//!
+//! The reason of `hold` and the rationale of a vote are the rest of the line.
+//!
+//! Comments are Markdown; commands are looked for in the plain text lines of a comment (see
+//! `markdown::text_lines`). Lines not starting with a trigger (by default `!g`, see `Triggers`)
+//! are not commands and are skipped.
+
+use std::fmt;
+use std::iter::Peekable;
+
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::markdown::text_lines;
+
+pub const DEFAULT_PREFIX: &str = "!g";
+
+/// What a line has to start with to be a command line.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Triggers {
+    /// Literal prefixes, e.g. `!g` or `/gk`.
+    pub prefixes: Vec<String>,
+    /// Accounts of the bot; `@account` (or BitBucket's `@{account}`) starts a command line.
+    pub mentions: Vec<String>,
+}
+
+impl Default for Triggers {
+    fn default() -> Triggers {
+        Triggers {
+            prefixes: vec![DEFAULT_PREFIX.to_string()],
+            mentions: Vec::new(),
+        }
+    }
+}
+
+impl Triggers {
+    pub fn is_trigger(&self, token: &str) -> bool {
+        if self.prefixes.iter().any(|prefix| prefix == token) {
+            return true;
+        }
+        match token.strip_prefix('@') {
+            Some(mention) => {
+                let mention = mention
+                    .trim_end_matches([':', ','])
+                    .trim_start_matches('{')
+                    .trim_end_matches('}');
+                self.mentions.iter().any(|account| account == mention)
+            }
+            None => false,
+        }
+    }
+
+    /// Trigger to use in examples shown to users.
+    pub fn example(&self) -> String {
+        match (self.prefixes.first(), self.mentions.first()) {
+            (Some(prefix), _) => prefix.clone(),
+            (None, Some(mention)) => format!("@{}", mention),
+            (None, None) => DEFAULT_PREFIX.to_string(),
+        }
+    }
+}
+
+/// Human readable summary of the language, in Markdown.
+pub fn syntax(triggers: &Triggers) -> String {
+    format!(
+        "\
+* `{0} +1`, `{0} -1`, `{0} 0` -- vote; `{0} +1 @abc1234` votes on the given revision, \
+`{0} -1 because <rationale>` explains the vote
+* `{0} +label`, `{0} -label` -- add or remove a label; `{0} +key:value` replaces other \
+`key:` labels, `{0} -key` removes them all
+* `{0} rfc <user>, <team>` -- request feedback from users or teams, any member may answer
+* `{0} will_revote` -- announce reviewing again
+* `{0} hold [reason]`, `{0} unhold` -- block merging until lifted; `{0} unhold <user>` and \
+`{0} unhold all` lift holds of others
+* `{0} retract`, `{0} retract vote`, `{0} retract rfc` -- withdraw your vote or RFC",
+        triggers.example()
+    )
+}
+
+/// Keywords of the language, e.g. for autocompletion.
+pub const KEYWORDS: &[&str] = &["rfc", "will_revote", "hold", "unhold", "retract"];
+
+/// Key of a `key:value` scoped label, `None` for plain labels.
+pub fn label_scope(label: &str) -> Option<&str> {
+    label.find(':').map(|idx| &label[..idx])
+}
+
+/// Location of a token: 1-based line of the comment and byte range within the plain text of
+/// that line.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Command {
+    /// `revision` is a prefix of the hash voted on, the current revision if not given.
+    Vote {
+        value: i32,
+        revision: Option<String>,
+        rationale: Option<String>,
+    },
+    AddLabel {
+        label: String,
+    },
+    RemoveLabel {
+        label: String,
+    },
+    Rfc {
+        targets: Vec<String>,
+    },
+    WillRevote,
+    Hold {
+        reason: Option<String>,
+    },
+    /// Lifts the holds of `target`, the own hold if not given.
+    Unhold {
+        target: Option<UnholdTarget>,
+    },
+    /// Withdraws the review status, only if it is of `target` when given.
+    Retract {
+        target: Option<RetractTarget>,
+    },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum UnholdTarget {
+    User(String),
+    All,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RetractTarget {
+    Vote,
+    Rfc,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum ParseErrorKind {
+    UnknownCommand {
+        token: String,
+    },
+    MissingArgument {
+        command: &'static str,
+        argument: &'static str,
+    },
+    InvalidVote {
+        token: String,
+    },
+    InvalidLabel {
+        token: String,
+    },
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: ",
+            self.span.line,
+            self.span.start + 1
+        )?;
+        match self.kind {
+            ParseErrorKind::UnknownCommand { ref token } => {
+                write!(f, "unrecognized command `{}`", token)
+            }
+            ParseErrorKind::MissingArgument { command, argument } => {
+                write!(f, "`{}` requires <{}>", command, argument)
+            }
+            ParseErrorKind::InvalidVote { ref token } => write!(f, "invalid vote `{}`", token),
+            ParseErrorKind::InvalidLabel { ref token } => write!(f, "invalid label `{}`", token),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub type ParseResult = Result<Spanned<Command>, ParseError>;
+
+/// Parses all command lines of a comment, in order.
+pub fn parse_comment(raw: &str, triggers: &Triggers) -> Vec<ParseResult> {
+    text_lines(raw)
+        .iter()
+        .filter_map(|line| parse_line(&line.text, line.line, triggers))
+        .flatten()
+        .collect()
+}
+
+/// Whether `raw` has text and all its lines are command lines.
+pub fn is_commands_only(raw: &str, triggers: &Triggers) -> bool {
+    let lines = text_lines(raw);
+    !lines.is_empty()
+        && lines
+            .iter()
+            .all(|line| parse_line(&line.text, line.line, triggers).is_some())
+}
+
+/// Parses a single line, `None` if it is not a command line.
+pub fn parse_line(line: &str, line_no: usize, triggers: &Triggers) -> Option<Vec<ParseResult>> {
+    let mut tokens = tokenize(line, line_no).peekable();
+    match tokens.next() {
+        Some(ref trigger) if triggers.is_trigger(trigger.node) => {}
+        _ => return None,
+    }
+
+    let mut res = Vec::new();
+    while let Some(token) = tokens.next() {
+        res.push(parse_command(token, &mut tokens));
+    }
+    Some(res)
+}
+
+/// Keywords starting with `prefix`.
+pub fn completions(prefix: &str) -> Vec<&'static str> {
+    KEYWORDS
+        .iter()
+        .cloned()
+        .filter(|keyword| keyword.starts_with(prefix))
+        .collect()
+}
+
+fn parse_command<'a, I>(token: Spanned<&'a str>, rest: &mut Peekable<I>) -> ParseResult
+where
+    I: Iterator<Item = Spanned<&'a str>>,
+{
+    lazy_static! {
+        static ref RE_VOTE: Regex = Regex::new(r"^(\+|-)?(\d+)$").unwrap();
+        static ref RE_USER: Regex = Regex::new(r"^[[:alpha:]][[:alnum:]_./-]*$").unwrap();
+        static ref RE_REVISION: Regex = Regex::new(r"^@([[:xdigit:]]{4,40})$").unwrap();
+        static ref RE_LABEL: Regex =
+            Regex::new(r"^(\+|-)([[:alpha:]][[:alnum:]_./-]*(:[[:alnum:]][[:alnum:]_./-]*)?)$")
+                .unwrap();
+    }
+    let span = token.span;
+    let spanned = |node| Ok(Spanned { node, span });
+
+    match token.node {
+        "rfc" => {
+            let mut targets = Vec::new();
+            let mut end = span.end;
+            // The list ends at the first token not made of names, which is left for the
+            // following commands.
+            while let Some(target) = rest.peek() {
+                let names: Vec<&str> = target
+                    .node
+                    .split(',')
+                    .map(|name| name.trim_start_matches('@'))
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                if names.is_empty() || !names.iter().all(|name| RE_USER.is_match(name)) {
+                    break;
+                }
+                end = target.span.end;
+                targets.extend(names.into_iter().map(str::to_string));
+                let continues = target.node.ends_with(',');
+                rest.next();
+                if !continues {
+                    break;
+                }
+            }
+            if targets.is_empty() {
+                return Err(ParseError {
+                    kind: ParseErrorKind::MissingArgument {
+                        command: "rfc",
+                        argument: "user",
+                    },
+                    span,
+                });
+            }
+            Ok(Spanned {
+                node: Command::Rfc { targets },
+                span: Span { end, ..span },
+            })
+        }
+        "will_revote" => spanned(Command::WillRevote),
+        "hold" => {
+            let reason: Vec<_> = rest.collect();
+            let end = reason.last().map_or(span.end, |word| word.span.end);
+            let reason = if reason.is_empty() {
+                None
+            } else {
+                Some(reason.iter().map(|word| word.node).join(" "))
+            };
+            Ok(Spanned {
+                node: Command::Hold { reason },
+                span: Span { end, ..span },
+            })
+        }
+        "unhold" => {
+            let target = match rest.peek().map(|target| target.node) {
+                Some("all") => Some(UnholdTarget::All),
+                Some(target) => match target.strip_prefix('@') {
+                    Some(user) if !user.is_empty() => Some(UnholdTarget::User(user.to_string())),
+                    Some(_) => None,
+                    None if RE_USER.is_match(target) && !KEYWORDS.contains(&target) => {
+                        Some(UnholdTarget::User(target.to_string()))
+                    }
+                    None => None,
+                },
+                None => None,
+            };
+            let end = match target {
+                Some(_) => rest.next().map_or(span.end, |target| target.span.end),
+                None => span.end,
+            };
+            Ok(Spanned {
+                node: Command::Unhold { target },
+                span: Span { end, ..span },
+            })
+        }
+        "retract" => {
+            let target = match rest.peek().map(|target| target.node) {
+                Some("vote") => Some(RetractTarget::Vote),
+                Some("rfc") => Some(RetractTarget::Rfc),
+                _ => None,
+            };
+            let end = match target {
+                Some(_) => rest.next().map_or(span.end, |target| target.span.end),
+                None => span.end,
+            };
+            Ok(Spanned {
+                node: Command::Retract { target },
+                span: Span { end, ..span },
+            })
+        }
+        "+" | "-" => Err(ParseError {
+            kind: ParseErrorKind::MissingArgument {
+                command: if token.node == "+" { "+" } else { "-" },
+                argument: "label",
+            },
+            span,
+        }),
+        cmd => {
+            if let Some(caps) = RE_VOTE.captures(cmd) {
+                let sign = caps.get(1).map_or("", |m| m.as_str());
+                let revision = rest
+                    .peek()
+                    .and_then(|next| RE_REVISION.captures(next.node))
+                    .map(|caps| caps[1].to_lowercase());
+                let end = match revision {
+                    Some(_) => rest.next().map_or(span.end, |next| next.span.end),
+                    None => span.end,
+                };
+                let (rationale, end) = match rest.peek() {
+                    Some(next) if next.node == "because" => {
+                        let words: Vec<_> = rest.skip(1).collect();
+                        let end = words.last().map_or(end, |word| word.span.end);
+                        let rationale = words.iter().map(|word| word.node).join(" ");
+                        (
+                            Some(rationale).filter(|rationale| !rationale.is_empty()),
+                            end,
+                        )
+                    }
+                    _ => (None, end),
+                };
+                let span = Span { end, ..span };
+                match caps[2].parse::<i32>() {
+                    Ok(value) => Ok(Spanned {
+                        node: Command::Vote {
+                            value: if sign == "-" { -value } else { value },
+                            revision,
+                            rationale,
+                        },
+                        span,
+                    }),
+                    Err(_) => Err(ParseError {
+                        kind: ParseErrorKind::InvalidVote {
+                            token: cmd.to_string(),
+                        },
+                        span,
+                    }),
+                }
+            } else if let Some(caps) = RE_LABEL.captures(cmd) {
+                let label = caps[2].to_string();
+                if &caps[1] == "-" {
+                    spanned(Command::RemoveLabel { label })
+                } else {
+                    spanned(Command::AddLabel { label })
+                }
+            } else if cmd.starts_with('+') || cmd.starts_with('-') {
+                Err(ParseError {
+                    kind: ParseErrorKind::InvalidLabel {
+                        token: cmd.to_string(),
+                    },
+                    span,
+                })
+            } else {
+                Err(ParseError {
+                    kind: ParseErrorKind::UnknownCommand {
+                        token: cmd.to_string(),
+                    },
+                    span,
+                })
+            }
+        }
+    }
+}
+
+fn tokenize(line: &str, line_no: usize) -> impl Iterator<Item = Spanned<&str>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (idx, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(token_start)) => {
+                tokens.push(token(line, line_no, token_start, idx));
+                start = None;
+            }
+            (false, None) => start = Some(idx),
+            _ => {}
+        }
+    }
+    if let Some(token_start) = start {
+        tokens.push(token(line, line_no, token_start, line.len()));
+    }
+    tokens.into_iter()
+}
+
+fn token(line: &str, line_no: usize, start: usize, end: usize) -> Spanned<&str> {
+    Spanned {
+        node: &line[start..end],
+        span: Span {
+            line: line_no,
+            start,
+            end,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Vec<ParseResult> {
+        parse_line(line, 1, &Triggers::default()).expect("not a command line")
+    }
+
+    fn commands(line: &str) -> Vec<Command> {
+        parse(line)
+            .into_iter()
+            .map(|res| res.expect("invalid command").node)
+            .collect()
+    }
+
+    fn error(line: &str) -> ParseError {
+        match parse(line).as_slice() {
+            [Err(e)] => e.clone(),
+            res => panic!("expected a single error, got {:?}", res),
+        }
+    }
+
+    fn span(start: usize, end: usize) -> Span {
+        Span {
+            line: 1,
+            start,
+            end,
+        }
+    }
+
+    fn vote(value: i32) -> Command {
+        Command::Vote {
+            value,
+            revision: None,
+            rationale: None,
+        }
+    }
+
+    #[test]
+    fn lines_without_trigger_are_not_commands() {
+        let triggers = Triggers::default();
+        assert_eq!(parse_line("+1 looks good", 1, &triggers), None);
+        assert_eq!(parse_line("see !g +1", 1, &triggers), None);
+        assert_eq!(parse_line("", 1, &triggers), None);
+        assert_eq!(parse_line("!g", 1, &triggers), Some(vec![]));
+    }
+
+    #[test]
+    fn mentions_trigger() {
+        let triggers = Triggers {
+            prefixes: vec![],
+            mentions: vec!["gatekeeper".to_string()],
+        };
+        for line in &["@gatekeeper +1", "@gatekeeper: +1", "@{gatekeeper}, +1"] {
+            let res = parse_line(line, 1, &triggers).unwrap();
+            assert_eq!(res.len(), 1, "{}", line);
+            assert_eq!(res[0].as_ref().unwrap().node, vote(1));
+        }
+        assert_eq!(parse_line("!g +1", 1, &triggers), None);
+        assert_eq!(parse_line("@someone +1", 1, &triggers), None);
+    }
+
+    #[test]
+    fn votes() {
+        assert_eq!(commands("!g +1"), vec![vote(1)]);
+        assert_eq!(commands("!g -2"), vec![vote(-2)]);
+        assert_eq!(commands("!g 0"), vec![vote(0)]);
+        assert_eq!(commands("!g +1 +1"), vec![vote(1), vote(1)]);
+        assert_eq!(parse("!g  -1")[0].as_ref().unwrap().span, span(4, 6));
+    }
+
+    #[test]
+    fn votes_on_revisions_with_rationale() {
+        let res = parse("!g -1 @ABC1234 because it breaks the build");
+        assert_eq!(
+            res,
+            vec![Ok(Spanned {
+                node: Command::Vote {
+                    value: -1,
+                    revision: Some("abc1234".to_string()),
+                    rationale: Some("it breaks the build".to_string()),
+                },
+                span: span(3, 42),
+            })]
+        );
+        assert_eq!(
+            commands("!g +1 because"),
+            vec![Command::Vote {
+                value: 1,
+                revision: None,
+                rationale: None,
+            }]
+        );
+        // Too short to be a revision, so it is taken for a command of its own.
+        let res = parse("!g +1 @abc");
+        assert_eq!(res[0].as_ref().unwrap().node, vote(1));
+        assert_eq!(
+            res[1].as_ref().unwrap_err().kind,
+            ParseErrorKind::UnknownCommand {
+                token: "@abc".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_votes() {
+        let e = error("!g +99999999999");
+        assert_eq!(
+            e.kind,
+            ParseErrorKind::InvalidVote {
+                token: "+99999999999".to_string()
+            }
+        );
+        assert_eq!(e.span, span(3, 15));
+    }
+
+    #[test]
+    fn labels() {
+        let add = |label: &str| Command::AddLabel {
+            label: label.to_string(),
+        };
+        assert_eq!(commands("!g +wip"), vec![add("wip")]);
+        assert_eq!(commands("!g +v2"), vec![add("v2")]);
+        assert_eq!(commands("!g +qa/passed-1.2"), vec![add("qa/passed-1.2")]);
+        assert_eq!(commands("!g +priority:high"), vec![add("priority:high")]);
+        assert_eq!(commands("!g +size:3"), vec![add("size:3")]);
+        assert_eq!(
+            commands("!g -priority"),
+            vec![Command::RemoveLabel {
+                label: "priority".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn invalid_labels() {
+        for token in &["+1.", "-2.", "+:x", "+a:", "+a:b:c", "+a!"] {
+            let e = error(&format!("!g {}", token));
+            assert_eq!(
+                e.kind,
+                ParseErrorKind::InvalidLabel {
+                    token: token.to_string()
+                }
+            );
+            assert_eq!(e.span, span(3, 3 + token.len()));
+        }
+        assert_eq!(
+            error("!g +").kind,
+            ParseErrorKind::MissingArgument {
+                command: "+",
+                argument: "label"
+            }
+        );
+    }
+
+    #[test]
+    fn rfc() {
+        let rfc = |targets: &[&str]| Command::Rfc {
+            targets: targets.iter().map(|target| target.to_string()).collect(),
+        };
+        assert_eq!(commands("!g rfc alice"), vec![rfc(&["alice"])]);
+        assert_eq!(
+            commands("!g rfc @alice, @bob,qa +1"),
+            vec![rfc(&["alice", "bob", "qa"]), vote(1)]
+        );
+        assert_eq!(
+            parse("!g rfc alice, bob")[0].as_ref().unwrap().span,
+            span(3, 17)
+        );
+        let e = error("!g rfc");
+        assert_eq!(
+            e.kind,
+            ParseErrorKind::MissingArgument {
+                command: "rfc",
+                argument: "user"
+            }
+        );
+        assert_eq!(e.span, span(3, 6));
+        assert_eq!(
+            parse("!g rfc +1"),
+            vec![
+                Err(ParseError {
+                    kind: ParseErrorKind::MissingArgument {
+                        command: "rfc",
+                        argument: "user"
+                    },
+                    span: span(3, 6),
+                }),
+                Ok(Spanned {
+                    node: vote(1),
+                    span: span(7, 9),
+                }),
+            ]
+        );
+        assert_eq!(
+            commands("!g rfc alice, -1"),
+            vec![rfc(&["alice"]), vote(-1)]
+        );
+        assert_eq!(
+            commands("!g rfc @bob +wip"),
+            vec![
+                rfc(&["bob"]),
+                Command::AddLabel {
+                    label: "wip".to_string()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn holds() {
+        assert_eq!(
+            commands("!g hold waiting for QA"),
+            vec![Command::Hold {
+                reason: Some("waiting for QA".to_string())
+            }]
+        );
+        assert_eq!(commands("!g hold"), vec![Command::Hold { reason: None }]);
+        assert_eq!(
+            commands("!g unhold +1"),
+            vec![Command::Unhold { target: None }, vote(1)]
+        );
+        assert_eq!(
+            commands("!g unhold @bob"),
+            vec![Command::Unhold {
+                target: Some(UnholdTarget::User("bob".to_string()))
+            }]
+        );
+        assert_eq!(
+            commands("!g unhold all"),
+            vec![Command::Unhold {
+                target: Some(UnholdTarget::All)
+            }]
+        );
+        assert_eq!(
+            commands("!g unhold retract"),
+            vec![
+                Command::Unhold { target: None },
+                Command::Retract { target: None }
+            ]
+        );
+    }
+
+    #[test]
+    fn other_commands() {
+        assert_eq!(commands("!g will_revote"), vec![Command::WillRevote]);
+        assert_eq!(
+            commands("!g retract rfc"),
+            vec![Command::Retract {
+                target: Some(RetractTarget::Rfc)
+            }]
+        );
+        assert_eq!(
+            commands("!g retract vote"),
+            vec![Command::Retract {
+                target: Some(RetractTarget::Vote)
+            }]
+        );
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let e = error("!g  frobnicate");
+        assert_eq!(
+            e.kind,
+            ParseErrorKind::UnknownCommand {
+                token: "frobnicate".to_string()
+            }
+        );
+        assert_eq!(e.span, span(4, 14));
+        assert_eq!(
+            e.to_string(),
+            "line 1, column 5: unrecognized command `frobnicate`"
+        );
+        let res = parse("!g +1 lgtm -1");
+        assert_eq!(res.len(), 3);
+        assert!(res[1].is_err());
+        assert_eq!(res[2].as_ref().unwrap().node, vote(-1));
+    }
+
+    #[test]
+    fn spans_are_byte_offsets_in_the_line() {
+        let e = match parse_line("!g ł +ok", 3, &Triggers::default())
+            .unwrap()
+            .as_slice()
+        {
+            [Err(e), Ok(_)] => e.clone(),
+            res => panic!("unexpected {:?}", res),
+        };
+        assert_eq!(
+            e.span,
+            Span {
+                line: 3,
+                start: 3,
+                end: 5
+            }
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let raw = "Looks good.\n\n!g +1 because nice\n\n> !g -1\n\n```\n!g -2\n```\n\n\
+                   * !g +wip";
+        let res: Vec<_> = parse_comment(raw, &Triggers::default())
+            .into_iter()
+            .map(|res| res.unwrap())
+            .collect();
+        assert_eq!(res.len(), 2);
+        assert_eq!(
+            res[0].node,
+            Command::Vote {
+                value: 1,
+                revision: None,
+                rationale: Some("nice".to_string()),
+            }
+        );
+        assert_eq!(res[0].span.line, 3);
+        assert_eq!(
+            res[1].node,
+            Command::AddLabel {
+                label: "wip".to_string()
+            }
+        );
+        assert_eq!(res[1].span.line, 11);
+    }
+
+    #[test]
+    fn commands_only() {
+        let triggers = Triggers::default();
+        assert!(is_commands_only("!g +1", &triggers));
+        assert!(is_commands_only("!g +1\n!g +wip", &triggers));
+        assert!(!is_commands_only("!g +1\n\nLooks good", &triggers));
+        assert!(!is_commands_only("", &triggers));
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(completions("re"), vec!["retract"]);
+        assert_eq!(completions("h"), vec!["hold"]);
+        assert!(completions("x").is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+
+use crate::command::{syntax, ParseError, Triggers};
+
+/// A command (or an approval) that was not applied, and why.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    pub user: String,
+    /// Comment with the command, `None` for approvals.
+    pub comment_id: Option<u32>,
+    pub date: DateTime<Utc>,
+    pub kind: DiagnosticKind,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum DiagnosticKind {
+    Parse(ParseError),
+    EditedCommand,
+    InlineCommand,
+    VoteOutOfRange { vote: i32, min: i32, max: i32 },
+    SelfVote,
+    PermissionDenied { action: String },
+    UnknownLabel { label: String },
+    UnknownRevision { revision: String, ambiguous: bool },
+    MissingRationale,
+}
+
+/// Text of a reply explaining the mistakes in a comment to its author, `diagnostics` being
+/// those of the comment.
+pub fn reply(diagnostics: &[&Diagnostic], triggers: &Triggers) -> String {
+    let user = diagnostics
+        .first()
+        .map_or("", |diagnostic| &diagnostic.user);
+    let reply = match *diagnostics {
+        [diagnostic] => format!(
+            "Gatekeeper ignored a command from @{}: {}.",
+            user, diagnostic.kind
+        ),
+        _ => format!(
+            "Gatekeeper ignored commands from @{}:\n\n{}",
+            user,
+            diagnostics
+                .iter()
+                .map(|diagnostic| format!("* {}", diagnostic.kind))
+                .join("\n")
+        ),
+    };
+    let parse_error = diagnostics
+        .iter()
+        .any(|diagnostic| matches!(diagnostic.kind, DiagnosticKind::Parse(_)));
+    if parse_error {
+        format!("{}\n\nValid syntax:\n\n{}", reply, syntax(triggers))
+    } else {
+        reply
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.comment_id {
+            Some(comment_id) => write!(f, "comment {} by {}: {}", comment_id, self.user, self.kind),
+            None => write!(f, "approval by {}: {}", self.user, self.kind),
+        }
+    }
+}
+
+impl fmt::Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DiagnosticKind::Parse(ref e) => write!(f, "{}", e),
+            DiagnosticKind::EditedCommand => write!(
+                f,
+                "commands in edited comments are ignored, post a new comment instead"
+            ),
+            DiagnosticKind::InlineCommand => write!(
+                f,
+                "commands in comments on code are ignored, post a general comment instead"
+            ),
+            DiagnosticKind::VoteOutOfRange { vote, min, max } => write!(
+                f,
+                "vote {:+} is out of the allowed range {:+}..{:+}",
+                vote, min, max
+            ),
+            DiagnosticKind::SelfVote => write!(f, "authors cannot vote on their own pull requests"),
+            DiagnosticKind::PermissionDenied { ref action } => {
+                write!(f, "not allowed to {}", action)
+            }
+            DiagnosticKind::UnknownLabel { ref label } => {
+                write!(f, "label `{}` is not allowed by the policy", label)
+            }
+            DiagnosticKind::MissingRationale => write!(
+                f,
+                "negative votes need a rationale, e.g. `-1 because <reason>`"
+            ),
+            DiagnosticKind::UnknownRevision {
+                ref revision,
+                ambiguous,
+            } => {
+                if ambiguous {
+                    write!(f, "revision `{}` is ambiguous", revision)
+                } else {
+                    write!(f, "revision `{}` is not a revision of this PR", revision)
+                }
+            }
+        }
+    }
+}
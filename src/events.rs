@@ -0,0 +1,964 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use crate::acl::{Action, ConfigGroups, Directory};
+use crate::bitbucket::{ActivityItem, Approval, Comment, PullRequest, Update};
+use crate::command::{label_scope, parse_comment, Command, RetractTarget, UnholdTarget};
+use crate::diagnostics::{Diagnostic, DiagnosticKind};
+use crate::policy::{EditedCommands, InlineCommands, Policy, RfcAnswers};
+use crate::revisions::{PolicyInvalidation, RevisionInspector, VoteInvalidation};
+use crate::status::is_status_comment;
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+use slog::{debug, trace};
+
+/// A single, already interpreted, thing that happened on a pull request.
+#[derive(PartialEq, Debug, Clone)]
+pub enum ReviewEvent {
+    /// `rebase_of` lists earlier revisions making the same change, see `revisions`, and
+    /// `invalidates` the users whose votes need reevaluation after the push, see
+    /// `Policy::invalidation`.
+    RevisionPushed {
+        hash: String,
+        rebase_of: Vec<String>,
+        invalidates: Vec<String>,
+        date: DateTime<Utc>,
+    },
+    /// Approval with BitBucket's button, counting as `vote`.
+    Approved {
+        user: String,
+        vote: i32,
+        date: DateTime<Utc>,
+    },
+    CommentPosted {
+        user: String,
+        comment_id: u32,
+        parent_id: Option<u32>,
+        date: DateTime<Utc>,
+    },
+    /// `revision` is the hash voted on, `None` for the current revision. `outdated` is set when
+    /// the vote on `revision` does not hold for the current revision.
+    VoteCast {
+        user: String,
+        vote: i32,
+        revision: Option<String>,
+        rationale: Option<String>,
+        outdated: bool,
+        date: DateTime<Utc>,
+    },
+    WillRevote {
+        user: String,
+        date: DateTime<Utc>,
+    },
+    /// `thread` is the comment in whose thread answers have to be posted, `None` if any
+    /// comment of a target answers.
+    RfcRequested {
+        user: String,
+        targets: Vec<RfcTarget>,
+        thread: Option<u32>,
+        date: DateTime<Utc>,
+    },
+    /// `comment_id` is the comment with the command.
+    LabelAdded {
+        user: String,
+        label: String,
+        comment_id: u32,
+        date: DateTime<Utc>,
+    },
+    /// A bare key as `label` removes all labels of its scope.
+    LabelRemoved {
+        user: String,
+        label: String,
+        comment_id: u32,
+        date: DateTime<Utc>,
+    },
+    HoldPlaced {
+        user: String,
+        reason: Option<String>,
+        date: DateTime<Utc>,
+    },
+    HoldLifted {
+        user: String,
+        target: UnholdTarget,
+        date: DateTime<Utc>,
+    },
+    Retracted {
+        user: String,
+        target: Option<RetractTarget>,
+        date: DateTime<Utc>,
+    },
+    CommandRejected {
+        diagnostic: Diagnostic,
+    },
+}
+
+/// Whom feedback is requested from: a single user, or a team any member of which may answer.
+#[derive(PartialEq, Debug, Clone)]
+pub struct RfcTarget {
+    pub name: String,
+    /// Members of the team, `None` if `name` is a user.
+    pub members: Option<Vec<String>>,
+}
+
+impl RfcTarget {
+    /// Resolves `name` as a team if `directory` knows such a group, as a user otherwise.
+    pub fn resolve(name: String, directory: &dyn Directory) -> RfcTarget {
+        RfcTarget {
+            members: directory.members(&name),
+            name,
+        }
+    }
+
+    /// Whether `user` can answer the request.
+    pub fn includes(&self, user: &str) -> bool {
+        match self.members {
+            Some(ref members) => members.iter().any(|member| member == user),
+            None => self.name == user,
+        }
+    }
+}
+
+impl ReviewEvent {
+    pub fn date(&self) -> DateTime<Utc> {
+        match *self {
+            ReviewEvent::RevisionPushed { date, .. }
+            | ReviewEvent::Approved { date, .. }
+            | ReviewEvent::CommentPosted { date, .. }
+            | ReviewEvent::VoteCast { date, .. }
+            | ReviewEvent::WillRevote { date, .. }
+            | ReviewEvent::RfcRequested { date, .. }
+            | ReviewEvent::LabelAdded { date, .. }
+            | ReviewEvent::LabelRemoved { date, .. }
+            | ReviewEvent::HoldPlaced { date, .. }
+            | ReviewEvent::HoldLifted { date, .. }
+            | ReviewEvent::Retracted { date, .. } => date,
+            ReviewEvent::CommandRejected { ref diagnostic } => diagnostic.date,
+        }
+    }
+
+    /// User who caused the event, `None` when BitBucket does not tell.
+    pub fn user(&self) -> Option<&str> {
+        match *self {
+            ReviewEvent::RevisionPushed { .. } => None,
+            ReviewEvent::Approved { ref user, .. }
+            | ReviewEvent::CommentPosted { ref user, .. }
+            | ReviewEvent::VoteCast { ref user, .. }
+            | ReviewEvent::WillRevote { ref user, .. }
+            | ReviewEvent::RfcRequested { ref user, .. }
+            | ReviewEvent::LabelAdded { ref user, .. }
+            | ReviewEvent::LabelRemoved { ref user, .. }
+            | ReviewEvent::HoldPlaced { ref user, .. }
+            | ReviewEvent::HoldLifted { ref user, .. }
+            | ReviewEvent::Retracted { ref user, .. } => Some(user),
+            ReviewEvent::CommandRejected { ref diagnostic } => Some(&diagnostic.user),
+        }
+    }
+}
+
+/// Translates the (chronologically ordered) activity into review events.
+///
+/// The events are ordered by date; commands of edited comments may end up after items that
+/// followed the comment in `activity`. `bot` is the account gatekeeper posts as.
+pub fn events_from_activity(
+    pr: &PullRequest,
+    activity: &[ActivityItem],
+    bot: &str,
+    policy: &Policy,
+    directory: &dyn Directory,
+    inspector: &dyn RevisionInspector,
+    logger: &slog::Logger,
+) -> Vec<ReviewEvent> {
+    let directory = &ConfigGroups {
+        groups: &policy.groups,
+        directory,
+    };
+    let revisions: Vec<&str> = activity
+        .iter()
+        .filter_map(|change| match *change {
+            ActivityItem::Update { ref update } => Some(update.source.commit.hash.as_str()),
+            _ => None,
+        })
+        .chain(pr.source.commit.as_ref().map(|commit| commit.hash.as_str()))
+        .collect();
+    let mut pushed: Vec<&str> = Vec::new();
+    let mut events = Vec::new();
+
+    for change in activity {
+        trace!(logger, "Change: {:?}", change);
+        match *change {
+            ActivityItem::Approval {
+                approval: Approval { ref user, date },
+            } => {
+                debug!(logger, "User {:?} approves", user.username);
+                let rejection = if !allows(pr, &user.username, Action::Vote, policy, directory) {
+                    Some(DiagnosticKind::PermissionDenied {
+                        action: Action::Vote.to_string(),
+                    })
+                } else if is_self_vote(pr, &user.username, policy) {
+                    Some(DiagnosticKind::SelfVote)
+                } else {
+                    None
+                };
+                if let Some(kind) = rejection {
+                    debug!(logger, "Ignoring approval: {}", kind);
+                    events.push(ReviewEvent::CommandRejected {
+                        diagnostic: Diagnostic {
+                            user: user.username.clone(),
+                            comment_id: None,
+                            date,
+                            kind,
+                        },
+                    });
+                    continue;
+                }
+                events.push(ReviewEvent::Approved {
+                    user: user.username.clone(),
+                    vote: policy.votes.approval,
+                    date,
+                });
+            }
+            ActivityItem::Comment { ref comment } if is_status_comment(comment, bot) => {
+                trace!(logger, "Skipping status comment {}", comment.id);
+            }
+            ActivityItem::Comment { ref comment } => {
+                events_from_comment(
+                    pr,
+                    comment,
+                    policy,
+                    directory,
+                    &revisions,
+                    &mut events,
+                    logger,
+                );
+            }
+            ActivityItem::Update {
+                update: Update { ref source, date },
+            } => {
+                let hash = source.commit.hash.as_str();
+                // Edits of the title or description are updates with the same source commit.
+                if pushed.last() == Some(&hash) {
+                    trace!(logger, "Revision {} is already current", hash);
+                    continue;
+                }
+                let rebase_of = if policy.invalidation.keep_on_rebase {
+                    pushed
+                        .iter()
+                        .filter(|old| **old != hash && inspector.same_change(old, hash))
+                        .map(|old| old.to_string())
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+                trace!(logger, "Revision {} is a rebase of {:?}", hash, rebase_of);
+                pushed.push(hash);
+                events.push(ReviewEvent::RevisionPushed {
+                    hash: hash.to_string(),
+                    rebase_of,
+                    invalidates: Vec::new(),
+                    date,
+                });
+            }
+        }
+    }
+
+    events.sort_by_key(ReviewEvent::date);
+    let invalidation = PolicyInvalidation {
+        rule: policy.invalidation.rule_for(&pr.destination.branch.name),
+        policy,
+        directory,
+        inspector,
+    };
+    let initial_hash = initial_hash(pr, &events);
+    settle_votes(&mut events, initial_hash.as_deref(), &invalidation);
+    check_label_changes(&mut events, pr, policy, directory);
+    events
+}
+
+/// The revision current before the first `RevisionPushed` of `events`.
+///
+/// Without any push in the activity the PR's source commit is the only revision there is.
+/// Otherwise votes preceding the first known push were cast on an unknown revision.
+pub fn initial_hash(pr: &PullRequest, events: &[ReviewEvent]) -> Option<String> {
+    if events
+        .iter()
+        .any(|event| matches!(*event, ReviewEvent::RevisionPushed { .. }))
+    {
+        None
+    } else {
+        pr.source.commit.as_ref().map(|commit| commit.hash.clone())
+    }
+}
+
+/// Decides which votes each push of `events` invalidates and whether votes on earlier
+/// revisions hold, so that folding the events needs nothing else.
+fn settle_votes(
+    events: &mut [ReviewEvent],
+    initial_hash: Option<&str>,
+    invalidation: &dyn VoteInvalidation,
+) {
+    let mut current = initial_hash.map(str::to_string);
+    let mut current_rebase_of: Vec<String> = Vec::new();
+    // Revision each valid vote was cast on, `None` if unknown.
+    let mut votes: HashMap<String, Option<String>> = HashMap::new();
+
+    for event in events.iter_mut() {
+        match *event {
+            ReviewEvent::RevisionPushed {
+                ref hash,
+                ref rebase_of,
+                ref mut invalidates,
+                ..
+            } => {
+                *invalidates = votes
+                    .iter()
+                    .filter(|(user, voted)| {
+                        !vote_holds(user, voted.as_deref(), hash, rebase_of, invalidation)
+                    })
+                    .map(|(user, _)| user.clone())
+                    .sorted()
+                    .collect();
+                for user in invalidates.iter() {
+                    votes.remove(user);
+                }
+                current = Some(hash.clone());
+                current_rebase_of = rebase_of.clone();
+            }
+            ReviewEvent::Approved { ref user, .. } => {
+                votes.insert(user.clone(), current.clone());
+            }
+            ReviewEvent::VoteCast {
+                ref user,
+                revision: Some(ref hash),
+                ref mut outdated,
+                ..
+            } => {
+                *outdated = match current {
+                    Some(ref current) => {
+                        current != hash
+                            && !vote_holds(
+                                user,
+                                Some(hash),
+                                current,
+                                &current_rebase_of,
+                                invalidation,
+                            )
+                    }
+                    None => true,
+                };
+                if *outdated {
+                    votes.remove(user);
+                } else {
+                    votes.insert(user.clone(), Some(hash.clone()));
+                }
+            }
+            ReviewEvent::VoteCast { ref user, .. } => {
+                votes.insert(user.clone(), current.clone());
+            }
+            ReviewEvent::WillRevote { ref user, .. }
+            | ReviewEvent::RfcRequested { ref user, .. } => {
+                votes.remove(user);
+            }
+            ReviewEvent::Retracted {
+                ref user, target, ..
+            } if target != Some(RetractTarget::Rfc) => {
+                votes.remove(user);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Rejects label changes dropping labels their author may not change: the other labels of the
+/// scope of an added scoped label, or all labels of the scope a bare key removes.
+fn check_label_changes(
+    events: &mut [ReviewEvent],
+    pr: &PullRequest,
+    policy: &Policy,
+    directory: &dyn Directory,
+) {
+    let mut labels: HashSet<String> = HashSet::new();
+
+    for event in events.iter_mut() {
+        let (user, comment_id, date, dropped) = match *event {
+            ReviewEvent::LabelAdded {
+                ref user,
+                ref label,
+                comment_id,
+                date,
+            } => {
+                let scope = label_scope(label);
+                let dropped: Vec<String> = labels
+                    .iter()
+                    .filter(|existing| {
+                        scope.is_some() && *existing != label && label_scope(existing) == scope
+                    })
+                    .cloned()
+                    .collect();
+                (user, comment_id, date, dropped)
+            }
+            ReviewEvent::LabelRemoved {
+                ref user,
+                ref label,
+                comment_id,
+                date,
+            } => {
+                let dropped: Vec<String> = labels
+                    .iter()
+                    .filter(|existing| {
+                        *existing == label || label_scope(existing) == Some(label.as_str())
+                    })
+                    .cloned()
+                    .collect();
+                (user, comment_id, date, dropped)
+            }
+            _ => continue,
+        };
+        let denied = dropped
+            .iter()
+            .sorted()
+            .find(|label| !allows(pr, user, Action::Label(label), policy, directory));
+        if let Some(label) = denied {
+            let diagnostic = Diagnostic {
+                user: user.clone(),
+                comment_id: Some(comment_id),
+                date,
+                kind: DiagnosticKind::PermissionDenied {
+                    action: Action::Label(label).to_string(),
+                },
+            };
+            *event = ReviewEvent::CommandRejected { diagnostic };
+            continue;
+        }
+        for label in &dropped {
+            labels.remove(label);
+        }
+        if let ReviewEvent::LabelAdded { ref label, .. } = *event {
+            labels.insert(label.clone());
+        }
+    }
+}
+
+/// Whether a vote of `user` on `voted` (`None` if unknown) still holds for `current`, a
+/// revision making the same change as the revisions in `rebase_of`.
+fn vote_holds(
+    user: &str,
+    voted: Option<&str>,
+    current: &str,
+    rebase_of: &[String],
+    invalidation: &dyn VoteInvalidation,
+) -> bool {
+    voted.is_some_and(|voted| rebase_of.iter().any(|hash| hash == voted))
+        || !invalidation.invalidates(user, voted, current)
+}
+
+fn events_from_comment(
+    pr: &PullRequest,
+    comment: &Comment,
+    policy: &Policy,
+    directory: &dyn Directory,
+    revisions: &[&str],
+    events: &mut Vec<ReviewEvent>,
+    logger: &slog::Logger,
+) {
+    let user = &comment.user.username;
+
+    if comment.deleted {
+        debug!(logger, "Skipping deleted comment {}", comment.id);
+        return;
+    }
+
+    events.push(ReviewEvent::CommentPosted {
+        user: user.clone(),
+        comment_id: comment.id,
+        parent_id: comment.parent.as_ref().map(|parent| parent.id),
+        date: comment.created_on,
+    });
+
+    if !comment.is_top_level() {
+        return;
+    }
+
+    let parsed_commands = parse_comment(&comment.content.raw, &policy.triggers);
+    if comment.inline.is_some()
+        && policy.inline_commands == InlineCommands::Reject
+        && !parsed_commands.is_empty()
+    {
+        debug!(
+            logger,
+            "Rejecting commands of inline comment {}", comment.id
+        );
+        events.push(rejected(
+            comment,
+            comment.created_on,
+            DiagnosticKind::InlineCommand,
+        ));
+        return;
+    }
+    let date = match comment.edited_on() {
+        None => comment.created_on,
+        Some(edited_on) if policy.edited_commands == EditedCommands::ApplyAtEditTime => {
+            debug!(
+                logger,
+                "Comment {} edited, applying at {}", comment.id, edited_on
+            );
+            edited_on
+        }
+        Some(edited_on) => {
+            if !parsed_commands.is_empty() {
+                debug!(
+                    logger,
+                    "Rejecting commands of edited comment {}", comment.id
+                );
+                events.push(rejected(comment, edited_on, DiagnosticKind::EditedCommand));
+            }
+            return;
+        }
+    };
+
+    for parsed in parsed_commands {
+        let cmd = match parsed {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                debug!(logger, "Invalid command in comment {}: {}", comment.id, e);
+                events.push(rejected(comment, date, DiagnosticKind::Parse(e)));
+                continue;
+            }
+        };
+        debug!(logger, "CMD: {:?}", cmd);
+        if let Err(kind) = validate(pr, comment, &cmd.node, policy, directory) {
+            debug!(
+                logger,
+                "Rejected command in comment {}: {}", comment.id, kind
+            );
+            events.push(rejected(comment, date, kind));
+            continue;
+        }
+        let revision = match cmd.node {
+            Command::Vote {
+                revision: Some(ref revision),
+                ..
+            } => match resolve_revision(revision, revisions) {
+                Ok(hash) => Some(hash),
+                Err(kind) => {
+                    debug!(
+                        logger,
+                        "Rejected command in comment {}: {}", comment.id, kind
+                    );
+                    events.push(rejected(comment, date, kind));
+                    continue;
+                }
+            },
+            _ => None,
+        };
+        let user = user.clone();
+        events.push(match cmd.node {
+            Command::Vote {
+                value, rationale, ..
+            } => ReviewEvent::VoteCast {
+                user,
+                vote: value,
+                revision,
+                rationale,
+                outdated: false,
+                date,
+            },
+            Command::Rfc { targets } => ReviewEvent::RfcRequested {
+                user,
+                targets: targets
+                    .into_iter()
+                    .map(|target| RfcTarget::resolve(target, directory))
+                    .collect(),
+                thread: match policy.rfc_answers {
+                    RfcAnswers::InThread => Some(comment.id),
+                    RfcAnswers::Anywhere => None,
+                },
+                date,
+            },
+            Command::WillRevote => ReviewEvent::WillRevote { user, date },
+            Command::AddLabel { label } => ReviewEvent::LabelAdded {
+                user,
+                label,
+                comment_id: comment.id,
+                date,
+            },
+            Command::RemoveLabel { label } => ReviewEvent::LabelRemoved {
+                user,
+                label,
+                comment_id: comment.id,
+                date,
+            },
+            Command::Hold { reason } => ReviewEvent::HoldPlaced { user, reason, date },
+            Command::Unhold { target } => ReviewEvent::HoldLifted {
+                target: target.unwrap_or_else(|| UnholdTarget::User(user.clone())),
+                user,
+                date,
+            },
+            Command::Retract { target } => ReviewEvent::Retracted { user, target, date },
+        });
+    }
+}
+
+/// Checks `command` against `policy`.
+fn validate(
+    pr: &PullRequest,
+    comment: &Comment,
+    command: &Command,
+    policy: &Policy,
+    directory: &dyn Directory,
+) -> Result<(), DiagnosticKind> {
+    let action = match *command {
+        Command::Vote { .. } => Some(Action::Vote),
+        Command::AddLabel { ref label } | Command::RemoveLabel { ref label } => {
+            Some(Action::Label(label))
+        }
+        Command::Rfc { .. } => Some(Action::Rfc),
+        Command::Hold { .. } | Command::Unhold { target: None } => Some(Action::Hold),
+        Command::Unhold {
+            target: Some(UnholdTarget::User(ref user)),
+        } if *user == comment.user.username => Some(Action::Hold),
+        Command::Unhold { .. } => Some(Action::Override),
+        Command::WillRevote | Command::Retract { .. } => None,
+    };
+    if let Some(action) = action {
+        if !allows(pr, &comment.user.username, action, policy, directory) {
+            return Err(DiagnosticKind::PermissionDenied {
+                action: action.to_string(),
+            });
+        }
+    }
+    if let Command::RemoveLabel { ref label } = *command {
+        // A bare key removes all labels of its scope.
+        let scope = format!("{}:*", label);
+        let action = Action::Label(&scope);
+        if label_scope(label).is_none()
+            && !allows(pr, &comment.user.username, action, policy, directory)
+        {
+            return Err(DiagnosticKind::PermissionDenied {
+                action: action.to_string(),
+            });
+        }
+    }
+
+    match *command {
+        Command::Vote { .. } if is_self_vote(pr, &comment.user.username, policy) => {
+            Err(DiagnosticKind::SelfVote)
+        }
+        Command::Vote { value, .. } if !policy.votes.contains(value) => {
+            Err(DiagnosticKind::VoteOutOfRange {
+                vote: value,
+                min: policy.votes.min,
+                max: policy.votes.max,
+            })
+        }
+        Command::Vote {
+            value,
+            rationale: None,
+            ..
+        } if value < 0 && policy.votes.negative_needs_rationale => {
+            Err(DiagnosticKind::MissingRationale)
+        }
+        Command::AddLabel { ref label } if !policy.allows_label(label) => {
+            Err(DiagnosticKind::UnknownLabel {
+                label: label.clone(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Full hash of the only known revision matching `prefix`.
+///
+/// BitBucket reports abbreviated hashes, so a longer `prefix` matches too.
+fn resolve_revision(prefix: &str, revisions: &[&str]) -> Result<String, DiagnosticKind> {
+    let matching: Vec<&str> = revisions
+        .iter()
+        .cloned()
+        .filter(|hash| hash.starts_with(prefix) || prefix.starts_with(hash))
+        .unique()
+        .collect();
+    match matching.as_slice() {
+        [hash] => Ok(hash.to_string()),
+        _ => Err(DiagnosticKind::UnknownRevision {
+            revision: prefix.to_string(),
+            ambiguous: matching.len() > 1,
+        }),
+    }
+}
+
+fn rejected(comment: &Comment, date: DateTime<Utc>, kind: DiagnosticKind) -> ReviewEvent {
+    ReviewEvent::CommandRejected {
+        diagnostic: Diagnostic {
+            user: comment.user.username.clone(),
+            comment_id: Some(comment.id),
+            date,
+            kind,
+        },
+    }
+}
+
+fn is_self_vote(pr: &PullRequest, user: &str, policy: &Policy) -> bool {
+    !policy.allow_self_votes && pr.author.username == user
+}
+
+fn allows(
+    pr: &PullRequest,
+    user: &str,
+    action: Action,
+    policy: &Policy,
+    directory: &dyn Directory,
+) -> bool {
+    let repo = &pr.destination.repository.full_name;
+    policy.acl.allows(user, action, repo, directory)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::acl::NoDirectory;
+    use crate::revisions::NoInspector;
+
+    /// Invalidates every vote of `voters`, votes of anyone else always hold.
+    struct Invalidates(&'static [&'static str]);
+
+    impl VoteInvalidation for Invalidates {
+        fn invalidates(&self, voter: &str, _voted: Option<&str>, _current: &str) -> bool {
+            self.0.contains(&voter)
+        }
+    }
+
+    fn date(minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2019, 1, 1).and_hms(0, minute, 0)
+    }
+
+    fn pushed(hash: &str, rebase_of: &[&str], minute: u32) -> ReviewEvent {
+        ReviewEvent::RevisionPushed {
+            hash: hash.to_string(),
+            rebase_of: rebase_of.iter().map(|hash| hash.to_string()).collect(),
+            invalidates: Vec::new(),
+            date: date(minute),
+        }
+    }
+
+    fn voted(user: &str, revision: Option<&str>, minute: u32) -> ReviewEvent {
+        ReviewEvent::VoteCast {
+            user: user.to_string(),
+            vote: 1,
+            revision: revision.map(str::to_string),
+            rationale: None,
+            outdated: false,
+            date: date(minute),
+        }
+    }
+
+    fn invalidates(event: &ReviewEvent) -> Vec<&str> {
+        match *event {
+            ReviewEvent::RevisionPushed {
+                ref invalidates, ..
+            } => invalidates.iter().map(String::as_str).collect(),
+            _ => panic!("not a push: {:?}", event),
+        }
+    }
+
+    fn outdated(event: &ReviewEvent) -> bool {
+        match *event {
+            ReviewEvent::VoteCast { outdated, .. } => outdated,
+            _ => panic!("not a vote: {:?}", event),
+        }
+    }
+
+    fn pull_request() -> PullRequest {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "title": "Fix things",
+            "state": "OPEN",
+            "links": {
+                "self": { "href": "https://api.bitbucket.org/2.0/repositories/o/r/pullrequests/1" },
+                "activity": {
+                    "href": "https://api.bitbucket.org/2.0/repositories/o/r/pullrequests/1/activity"
+                },
+            },
+            "author": { "username": "author" },
+            "source": {
+                "branch": { "name": "fix" },
+                "commit": { "hash": "a" },
+            },
+            "destination": {
+                "branch": { "name": "master" },
+                "commit": null,
+                "repository": { "full_name": "o/r" },
+            },
+            "created_on": "2019-01-01T00:00:00Z",
+            "updated_on": "2019-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    fn comment(id: u32, user: &str, raw: &str) -> ActivityItem {
+        serde_json::from_value(serde_json::json!({
+            "comment": {
+                "id": id,
+                "parent": null,
+                "content": { "raw": raw },
+                "user": { "username": user },
+                "created_on": format!("2019-01-01T00:{:02}:00Z", id),
+                "updated_on": null,
+                "inline": null,
+                "resolution": null,
+            }
+        }))
+        .unwrap()
+    }
+
+    /// Events of `activity` with only `lead` allowed to change `priority:high` labels.
+    fn label_events(activity: &[ActivityItem]) -> Vec<ReviewEvent> {
+        let acl = serde_json::json!({ "labels": { "priority:high": { "users": ["lead"] } } });
+        label_events_with(acl, activity)
+    }
+
+    fn label_events_with(acl: serde_json::Value, activity: &[ActivityItem]) -> Vec<ReviewEvent> {
+        let policy: Policy = serde_json::from_value(serde_json::json!({ "acl": acl })).unwrap();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        events_from_activity(
+            &pull_request(),
+            activity,
+            "bot",
+            &policy,
+            &NoDirectory,
+            &NoInspector,
+            &logger,
+        )
+        .into_iter()
+        .filter(|event| !matches!(*event, ReviewEvent::CommentPosted { .. }))
+        .collect()
+    }
+
+    fn denied(event: &ReviewEvent) -> Option<&str> {
+        match *event {
+            ReviewEvent::CommandRejected {
+                diagnostic:
+                    Diagnostic {
+                        kind: DiagnosticKind::PermissionDenied { ref action },
+                        ..
+                    },
+            } => Some(action),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bare_key_removal_acts_on_whole_scope() {
+        let events = label_events(&[
+            comment(1, "lead", "!g +priority:high"),
+            comment(2, "alice", "!g -priority"),
+            comment(3, "alice", "!g -priority:low"),
+        ]);
+        assert_eq!(denied(&events[1]), Some("change label `priority:high`"));
+        assert!(matches!(events[2], ReviewEvent::LabelRemoved { .. }));
+
+        let acl = serde_json::json!({ "labels": { "priority:*": { "users": ["lead"] } } });
+        let events = label_events_with(acl, &[comment(1, "alice", "!g -priority")]);
+        assert_eq!(denied(&events[0]), Some("change label `priority:*`"));
+    }
+
+    #[test]
+    fn scoped_add_checks_replaced_labels() {
+        let events = label_events(&[
+            comment(1, "lead", "!g +priority:high"),
+            comment(2, "alice", "!g +priority:low"),
+            comment(3, "lead", "!g +priority:low"),
+            comment(4, "alice", "!g +priority:medium"),
+        ]);
+        assert_eq!(denied(&events[0]), None);
+        assert_eq!(denied(&events[1]), Some("change label `priority:high`"));
+        assert_eq!(denied(&events[2]), None);
+        // `priority:high` is gone, nothing protected is replaced.
+        assert_eq!(denied(&events[3]), None);
+    }
+
+    #[test]
+    fn push_invalidates_votes_per_policy() {
+        let mut events = vec![
+            voted("bob", None, 1),
+            voted("alice", None, 2),
+            pushed("b", &[], 3),
+        ];
+        settle_votes(&mut events, Some("a"), &Invalidates(&["alice", "bob"]));
+        assert_eq!(invalidates(&events[2]), vec!["alice", "bob"]);
+
+        let mut events = vec![voted("alice", None, 1), pushed("b", &[], 2)];
+        settle_votes(&mut events, Some("a"), &Invalidates(&["bob"]));
+        assert!(invalidates(&events[1]).is_empty());
+    }
+
+    #[test]
+    fn invalidated_votes_are_not_invalidated_again() {
+        let mut events = vec![
+            voted("alice", None, 1),
+            pushed("b", &[], 2),
+            pushed("c", &[], 3),
+        ];
+        settle_votes(&mut events, Some("a"), &Invalidates(&["alice"]));
+        assert_eq!(invalidates(&events[1]), vec!["alice"]);
+        assert!(invalidates(&events[2]).is_empty());
+    }
+
+    #[test]
+    fn rebase_keeps_votes() {
+        let mut events = vec![voted("alice", None, 1), pushed("b", &["a"], 2)];
+        settle_votes(&mut events, Some("a"), &Invalidates(&["alice"]));
+        assert!(invalidates(&events[1]).is_empty());
+    }
+
+    #[test]
+    fn withdrawn_votes_are_not_invalidated() {
+        let mut events = vec![
+            voted("alice", None, 1),
+            ReviewEvent::WillRevote {
+                user: "alice".to_string(),
+                date: date(2),
+            },
+            pushed("b", &[], 3),
+        ];
+        settle_votes(&mut events, Some("a"), &Invalidates(&["alice"]));
+        assert!(invalidates(&events[2]).is_empty());
+    }
+
+    #[test]
+    fn pinned_votes_on_earlier_revisions() {
+        let mut events = vec![
+            pushed("a", &[], 1),
+            pushed("b", &["a"], 2),
+            voted("alice", Some("a"), 3),
+            voted("bob", Some("a"), 4),
+            voted("carol", Some("b"), 5),
+            pushed("c", &[], 6),
+        ];
+        settle_votes(&mut events, None, &Invalidates(&["bob", "carol"]));
+        // `b` rebases `a`, so alice's and bob's votes hold; carol voted on the current revision.
+        assert!(!outdated(&events[2]));
+        assert!(!outdated(&events[3]));
+        assert!(!outdated(&events[4]));
+        assert_eq!(invalidates(&events[5]), vec!["bob", "carol"]);
+
+        let mut events = vec![
+            pushed("a", &[], 1),
+            pushed("b", &[], 2),
+            voted("bob", Some("a"), 3),
+        ];
+        settle_votes(&mut events, None, &Invalidates(&["bob"]));
+        assert!(outdated(&events[2]));
+    }
+
+    #[test]
+    fn pinned_votes_without_known_revision_are_outdated() {
+        let mut events = vec![voted("alice", Some("a"), 1)];
+        settle_votes(&mut events, None, &Invalidates(&[]));
+        assert!(outdated(&events[0]));
+    }
+}
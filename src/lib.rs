@@ -2,20 +2,38 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+pub mod acl;
 pub mod bitbucket;
+pub mod command;
+pub mod diagnostics;
+pub mod events;
+pub mod markdown;
+pub mod permissions;
+pub mod policy;
+pub mod revisions;
+pub mod status;
+pub mod threads;
+pub mod title;
 
+use crate::acl::Directory;
 use crate::bitbucket::ActivityItem;
-use crate::bitbucket::Approval;
 use crate::bitbucket::PullRequest;
+use crate::command::{label_scope, RetractTarget, UnholdTarget};
+use crate::diagnostics::Diagnostic;
+use crate::events::{events_from_activity, initial_hash, ReviewEvent, RfcTarget};
+use crate::policy::{Policy, VoteScale};
+use crate::revisions::{Interdiff, RevisionInspector};
+use crate::threads::{threads_from_activity, Thread};
+use crate::title::is_wip;
 
 use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
+use chrono::{DateTime, Utc};
 use failure::Error;
-use lazy_static::lazy_static;
-use regex::Regex;
-use slog::{debug, error, o, trace, warn};
+use itertools::Itertools;
+use slog::{debug, trace};
 
 #[derive(Debug)]
 pub struct RepositoryURLs {
@@ -56,198 +74,1020 @@ impl PullrequestIdURLs {
     }
 }
 
-#[derive(PartialEq, Debug)]
-pub struct UserCommand {
+#[derive(Debug)]
+pub enum ReviewStatus {
+    NoReview,
+    /// `vote_hash` is `None` when the revision voted on is unknown.
+    Voted {
+        vote: i32,
+        vote_hash: Option<String>,
+        voted_on: DateTime<Utc>,
+        rationale: Option<String>,
+    },
+    VoteNeedReevaluation {
+        voted: i32,
+        vote_hash: Option<String>,
+        voted_on: DateTime<Utc>,
+        rationale: Option<String>,
+        invalidated_on: DateTime<Utc>,
+    },
+    WantsToReviewAgain {
+        voted: Option<i32>,
+        requested_on: DateTime<Utc>,
+    },
+    /// Some of `requests` are still unanswered. `thread` is the comment in whose thread
+    /// answers have to be posted, `None` if any comment counts.
+    RFC {
+        requests: Vec<RfcRequest>,
+        thread: Option<u32>,
+        requested_on: DateTime<Utc>,
+    },
+    RFCAnswered {
+        requests: Vec<RfcRequest>,
+        thread: Option<u32>,
+        requested_on: DateTime<Utc>,
+        answered_on: DateTime<Utc>,
+    },
+}
+
+/// Feedback requested from a single target of an `rfc` command.
+#[derive(Debug)]
+pub struct RfcRequest {
+    pub target: RfcTarget,
+    pub answer: Option<RfcAnswer>,
+}
+
+#[derive(Debug)]
+pub struct RfcAnswer {
     pub user: String,
-    pub command: String,
+    pub comment_id: u32,
+    pub answered_on: DateTime<Utc>,
 }
 
-impl UserCommand {
-    pub fn new(user: &str, command: &str) -> UserCommand {
-        let command = if command.starts_with("\\+") {
-            command.trim_start_matches('\\')
-        } else {
-            command
-        };
-        UserCommand {
-            user: user.to_string(),
-            command: command.to_string(),
+impl ReviewStatus {
+    /// Moment since which the status holds, `None` for `NoReview`.
+    pub fn since(&self) -> Option<DateTime<Utc>> {
+        match *self {
+            ReviewStatus::NoReview => None,
+            ReviewStatus::Voted { voted_on, .. } => Some(voted_on),
+            ReviewStatus::VoteNeedReevaluation { invalidated_on, .. } => Some(invalidated_on),
+            ReviewStatus::WantsToReviewAgain { requested_on, .. } => Some(requested_on),
+            ReviewStatus::RFC { requested_on, .. } => Some(requested_on),
+            ReviewStatus::RFCAnswered { answered_on, .. } => Some(answered_on),
         }
     }
-}
 
-#[derive(Debug)]
-pub enum ReviewStatus {
-    NoReview,
-    Voted { vote: i32, vote_hash: String },
-    VoteNeedReevaluation { voted: i32, vote_hash: String },
-    WantsToReviewAgain { voted: Option<i32> },
-    RFC { user: String },
-    RFCAnswered { user: String },
+    /// Rationale of the vote, if any.
+    pub fn rationale(&self) -> Option<&str> {
+        match *self {
+            ReviewStatus::Voted { ref rationale, .. }
+            | ReviewStatus::VoteNeedReevaluation { ref rationale, .. } => rationale.as_deref(),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct PullRequestState {
+/// Review-related state of a pull request, folded from `ReviewEvent`s.
+#[derive(Debug, Default)]
+pub struct ReviewState {
     pub review_status: HashMap<String, ReviewStatus, RandomState>,
-    logger: slog::Logger,
-    pub urls: PullrequestIdURLs,
-    pub pr: PullRequest,
     pub labels: HashSet<String>,
     pub current_hash: Option<String>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Top-level comment of the thread of each comment.
+    pub thread_roots: HashMap<u32, u32>,
+    /// Holds placed with commands, at most one per user.
+    pub holds: Vec<Hold>,
 }
 
-impl PullRequestState {
-    pub fn from_activity(
-        pr: PullRequest,
-        activity: Vec<ActivityItem>,
-        urls: PullrequestIdURLs,
-        logger: &slog::Logger,
-    ) -> Result<PullRequestState, Error> {
-        lazy_static! {
-            static ref RE_VOTE: Regex = Regex::new(r"^(\\?\+|-)?\d$").unwrap();
-            static ref RE_LABEL: Regex = Regex::new(r"^(\\?\+|-)([[:alpha:]]*)$").unwrap();
-        }
-        let mut pr_state = PullRequestState::new(pr, urls, logger);
-
-        for change in activity {
-            trace!(pr_state.logger, "Change: {:?}", change);
-            match change {
-                ActivityItem::Approval {
-                    approval: Approval { user },
-                } => {
-                    let approve_user = user.username.to_string();
-                    debug!(pr_state.logger, "User {:?} approves", approve_user);
-                    pr_state.review_status.insert(
-                        approve_user,
-                        ReviewStatus::Voted {
-                            vote: 1,
-                            vote_hash: pr_state.current_hash.clone().unwrap(),
-                        },
-                    );
-                }
+/// A request not to merge the pull request, regardless of votes.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Hold {
+    pub user: String,
+    pub reason: Option<String>,
+    pub placed_on: DateTime<Utc>,
+}
 
-                ActivityItem::Comment { comment } => {
-                    let comment_user = comment.user.username;
+impl ReviewState {
+    /// Folds `events` starting at `initial_hash`, the revision assumed to be current before
+    /// the first `RevisionPushed`.
+    pub fn from_events<'a, I>(initial_hash: Option<String>, events: I) -> ReviewState
+    where
+        I: IntoIterator<Item = &'a ReviewEvent>,
+    {
+        let state = ReviewState {
+            current_hash: initial_hash,
+            ..ReviewState::default()
+        };
+        events.into_iter().fold(state, |mut state, event| {
+            state.apply(event);
+            state
+        })
+    }
 
-                    for status in pr_state.review_status.values_mut() {
-                        let should_update = match *status {
-                            ReviewStatus::RFC { ref user } if *user == comment_user => true,
-                            _ => false,
+    pub fn apply(&mut self, event: &ReviewEvent) {
+        match *event {
+            ReviewEvent::RevisionPushed {
+                ref hash,
+                ref invalidates,
+                date,
+                ..
+            } => {
+                self.current_hash = Some(hash.clone());
+                for user in invalidates {
+                    let status = match self.review_status.get_mut(user) {
+                        Some(status) => status,
+                        None => continue,
+                    };
+                    let should_update = match *status {
+                        ReviewStatus::Voted {
+                            vote,
+                            ref vote_hash,
+                            voted_on,
+                            ref rationale,
+                        } => Some((vote, vote_hash.clone(), voted_on, rationale.clone())),
+                        _ => None,
+                    };
+                    if let Some((vote, vote_hash, voted_on, rationale)) = should_update {
+                        *status = ReviewStatus::VoteNeedReevaluation {
+                            voted: vote,
+                            vote_hash,
+                            voted_on,
+                            rationale,
+                            invalidated_on: date,
                         };
-                        if should_update {
-                            *status = ReviewStatus::RFCAnswered {
-                                user: comment_user.clone(),
-                            };
-                        }
                     }
+                }
+            }
 
-                    if comment.parent == None {
-                        let user_review = pr_state
-                            .review_status
-                            .entry(comment_user.clone())
-                            .or_insert(ReviewStatus::NoReview);
-
-                        for comment_line in comment.content.raw.lines() {
-                            trace!(pr_state.logger, "Line: {}", comment_line);
-                            let mut splitter = comment_line.split_whitespace();
-                            if Some("!g") == splitter.next() {
-                                while let Some(cmd) = splitter.next() {
-                                    debug!(pr_state.logger, "CMD: {}", cmd);
-                                    match cmd {
-                                        vote if RE_VOTE.is_match(vote) => {
-                                            *user_review = ReviewStatus::Voted {
-                                                vote: cmd
-                                                    .trim_start_matches('\\')
-                                                    .parse::<i32>()?,
-                                                vote_hash: pr_state.current_hash.clone().unwrap(),
-                                            }
-                                        }
-                                        "rfc" => {
-                                            if let Some(wait_for_user) = splitter.next() {
-                                                debug!(pr_state.logger, "ARG: {}", wait_for_user);
-                                                *user_review = ReviewStatus::RFC {
-                                                    user: wait_for_user.to_string(),
-                                                }
-                                            }
-                                        }
-                                        "will\\_revote" => {
-                                            let voted = match *user_review {
-                                                ReviewStatus::WantsToReviewAgain { voted } => voted,
-                                                ReviewStatus::Voted { vote, .. } => Some(vote),
-                                                ReviewStatus::VoteNeedReevaluation {
-                                                    voted,
-                                                    ..
-                                                } => Some(voted),
-                                                _ => None,
-                                            };
-                                            *user_review =
-                                                ReviewStatus::WantsToReviewAgain { voted }
-                                        }
-                                        _ => {
-                                            if let Some(caps) = RE_LABEL.captures(cmd) {
-                                                let direction = caps
-                                                    .get(1)
-                                                    .ok_or_else(|| failure::err_msg("Internal error: RE_LABEL matched but caps.get(1) failed"))?
-                                                    .as_str()
-                                                    .trim_start_matches('\\');
-                                                let label = caps
-                                                    .get(2)
-                                                    .ok_or_else(|| failure::err_msg("Internal error: RE_LABEL matched but caps.get(2) failed"))?
-                                                    .as_str()
-                                                    .to_string();
-                                                match direction {
-                                                    "+" => {
-                                                        pr_state.labels.insert(label);
-                                                    }
-                                                    "-" => {
-                                                        pr_state.labels.remove(&label);
-                                                    }
-                                                    _ => error!(pr_state.logger, "Internal error: RE_LABEL matched '{}' but caps.get(1) returned neither +/-.", cmd),
-                                                }
-                                            } else {
-                                                warn!(pr_state.logger, "Unrecognized cmd: {}", cmd);
-                                            }
-                                        }
-                                    }
-                                }
+            ReviewEvent::CommentPosted {
+                ref user,
+                comment_id,
+                parent_id,
+                date,
+            } => {
+                let thread_root = parent_id.map_or(comment_id, |parent_id| {
+                    self.thread_roots
+                        .get(&parent_id)
+                        .cloned()
+                        .unwrap_or(parent_id)
+                });
+                self.thread_roots.insert(comment_id, thread_root);
+
+                for (requester, status) in &mut self.review_status {
+                    if requester == user {
+                        continue;
+                    }
+                    let should_update = match *status {
+                        ReviewStatus::RFC {
+                            ref mut requests,
+                            thread,
+                            requested_on,
+                        } if thread.is_none_or(|thread| thread == thread_root) => {
+                            for request in requests.iter_mut().filter(|request| {
+                                request.answer.is_none() && request.target.includes(user)
+                            }) {
+                                request.answer = Some(RfcAnswer {
+                                    user: user.clone(),
+                                    comment_id,
+                                    answered_on: date,
+                                });
+                            }
+                            if requests.iter().all(|request| request.answer.is_some()) {
+                                Some((std::mem::take(requests), thread, requested_on))
+                            } else {
+                                None
                             }
                         }
+                        _ => None,
+                    };
+                    if let Some((requests, thread, requested_on)) = should_update {
+                        *status = ReviewStatus::RFCAnswered {
+                            requests,
+                            thread,
+                            requested_on,
+                            answered_on: date,
+                        };
                     }
                 }
+                if parent_id.is_none() {
+                    self.review_status
+                        .entry(user.clone())
+                        .or_insert(ReviewStatus::NoReview);
+                }
+            }
 
-                ActivityItem::Update { update } => {
-                    pr_state.current_hash = Some(update.source.commit.hash);
-                    for status in pr_state.review_status.values_mut() {
-                        let should_update = match *status {
-                            ReviewStatus::Voted {
-                                vote,
-                                ref vote_hash,
-                            } => Some((vote, vote_hash.clone())),
-                            _ => None,
-                        };
-                        if let Some((vote, vote_hash)) = should_update {
-                            *status = ReviewStatus::VoteNeedReevaluation {
-                                voted: vote,
-                                vote_hash,
-                            };
+            ReviewEvent::Approved {
+                ref user,
+                vote,
+                date,
+            } => {
+                self.review_status.insert(
+                    user.clone(),
+                    ReviewStatus::Voted {
+                        vote,
+                        vote_hash: self.current_hash.clone(),
+                        voted_on: date,
+                        rationale: None,
+                    },
+                );
+            }
+
+            ReviewEvent::VoteCast {
+                ref user,
+                vote,
+                ref revision,
+                ref rationale,
+                outdated,
+                date,
+            } => {
+                let status = match *revision {
+                    Some(ref hash) if outdated => ReviewStatus::VoteNeedReevaluation {
+                        voted: vote,
+                        vote_hash: Some(hash.clone()),
+                        voted_on: date,
+                        rationale: rationale.clone(),
+                        invalidated_on: date,
+                    },
+                    Some(ref hash) => ReviewStatus::Voted {
+                        vote,
+                        vote_hash: Some(hash.clone()),
+                        voted_on: date,
+                        rationale: rationale.clone(),
+                    },
+                    None => ReviewStatus::Voted {
+                        vote,
+                        vote_hash: self.current_hash.clone(),
+                        voted_on: date,
+                        rationale: rationale.clone(),
+                    },
+                };
+                self.review_status.insert(user.clone(), status);
+            }
+
+            ReviewEvent::WillRevote { ref user, date } => {
+                let user_review = self
+                    .review_status
+                    .entry(user.clone())
+                    .or_insert(ReviewStatus::NoReview);
+                let voted = match *user_review {
+                    ReviewStatus::WantsToReviewAgain { voted, .. } => voted,
+                    ReviewStatus::Voted { vote, .. } => Some(vote),
+                    ReviewStatus::VoteNeedReevaluation { voted, .. } => Some(voted),
+                    _ => None,
+                };
+                *user_review = ReviewStatus::WantsToReviewAgain {
+                    voted,
+                    requested_on: date,
+                };
+            }
+
+            ReviewEvent::RfcRequested {
+                ref user,
+                ref targets,
+                thread,
+                date,
+            } => {
+                self.review_status.insert(
+                    user.clone(),
+                    ReviewStatus::RFC {
+                        requests: targets
+                            .iter()
+                            .map(|target| RfcRequest {
+                                target: target.clone(),
+                                answer: None,
+                            })
+                            .collect(),
+                        thread,
+                        requested_on: date,
+                    },
+                );
+            }
+
+            ReviewEvent::LabelAdded { ref label, .. } => {
+                if let Some(scope) = label_scope(label) {
+                    self.labels
+                        .retain(|existing| label_scope(existing) != Some(scope));
+                }
+                self.labels.insert(label.clone());
+            }
+
+            ReviewEvent::LabelRemoved { ref label, .. } => {
+                // A bare key removes all labels of its scope.
+                self.labels.retain(|existing| {
+                    existing != label && label_scope(existing) != Some(label.as_str())
+                });
+            }
+
+            ReviewEvent::HoldPlaced {
+                ref user,
+                ref reason,
+                date,
+            } => {
+                self.holds.retain(|hold| hold.user != *user);
+                self.holds.push(Hold {
+                    user: user.clone(),
+                    reason: reason.clone(),
+                    placed_on: date,
+                });
+            }
+
+            ReviewEvent::HoldLifted { ref target, .. } => match *target {
+                UnholdTarget::User(ref user) => self.holds.retain(|hold| hold.user != *user),
+                UnholdTarget::All => self.holds.clear(),
+            },
+
+            ReviewEvent::Retracted {
+                ref user, target, ..
+            } => {
+                if let Some(status) = self.review_status.get_mut(user) {
+                    let retracted = match *status {
+                        ReviewStatus::Voted { .. }
+                        | ReviewStatus::VoteNeedReevaluation { .. }
+                        | ReviewStatus::WantsToReviewAgain { .. } => {
+                            target != Some(RetractTarget::Rfc)
+                        }
+                        ReviewStatus::RFC { .. } | ReviewStatus::RFCAnswered { .. } => {
+                            target != Some(RetractTarget::Vote)
                         }
+                        ReviewStatus::NoReview => false,
+                    };
+                    if retracted {
+                        *status = ReviewStatus::NoReview;
                     }
                 }
             }
+
+            ReviewEvent::CommandRejected { ref diagnostic } => {
+                self.diagnostics.push(diagnostic.clone());
+            }
+        }
+    }
+}
+
+/// Whether a pull request may be merged as far as reviews are concerned.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Verdict {
+    Approved {
+        by: Vec<String>,
+    },
+    Vetoed {
+        by: Vec<String>,
+    },
+    /// Not vetoed, but there are holds placed by `by`.
+    OnHold {
+        by: Vec<String>,
+    },
+    Pending,
+}
+
+impl ReviewState {
+    /// A `min` vote vetoes the PR even after new pushes, a `max` vote approves it only while
+    /// it is valid for the current revision.
+    ///
+    /// Holds are not considered, see `PullRequestState::verdict`.
+    pub fn verdict(&self, votes: &VoteScale) -> Verdict {
+        let vetoed_by: Vec<String> = self
+            .review_status
+            .iter()
+            .filter(|&(_, status)| match *status {
+                ReviewStatus::Voted { vote, .. } => vote == votes.min,
+                ReviewStatus::VoteNeedReevaluation { voted, .. } => voted == votes.min,
+                _ => false,
+            })
+            .map(|(user, _)| user.clone())
+            .sorted()
+            .collect();
+        if !vetoed_by.is_empty() {
+            return Verdict::Vetoed { by: vetoed_by };
+        }
+
+        let approved_by: Vec<String> = self
+            .review_status
+            .iter()
+            .filter(|&(_, status)| match *status {
+                ReviewStatus::Voted { vote, .. } => vote == votes.max,
+                _ => false,
+            })
+            .map(|(user, _)| user.clone())
+            .sorted()
+            .collect();
+        if !approved_by.is_empty() {
+            return Verdict::Approved { by: approved_by };
         }
 
-        Ok(pr_state)
+        Verdict::Pending
     }
+}
 
-    fn new(pr: PullRequest, urls: PullrequestIdURLs, logger: &slog::Logger) -> PullRequestState {
-        let logger = logger.new(o!());
-        PullRequestState {
-            review_status: HashMap::new(),
-            logger,
+#[derive(Debug)]
+pub struct PullRequestState {
+    pub review: ReviewState,
+    pub timeline: Vec<ReviewEvent>,
+    pub threads: Vec<Thread>,
+    /// Changes since the votes needing reevaluation, by user.
+    pub interdiffs: Vec<Interdiff>,
+    pub urls: PullrequestIdURLs,
+    pub pr: PullRequest,
+    pub policy: Policy,
+}
+
+impl PullRequestState {
+    /// `bot` is the account gatekeeper posts as.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_activity(
+        pr: PullRequest,
+        activity: Vec<ActivityItem>,
+        urls: PullrequestIdURLs,
+        bot: &str,
+        policy: &Policy,
+        directory: &dyn Directory,
+        inspector: &dyn RevisionInspector,
+        logger: &slog::Logger,
+    ) -> Result<PullRequestState, Error> {
+        let timeline =
+            events_from_activity(&pr, &activity, bot, policy, directory, inspector, logger);
+        trace!(logger, "Timeline: {:?}", timeline);
+
+        let initial_hash = initial_hash(&pr, &timeline);
+        debug!(logger, "Initial hash: {:?}", initial_hash);
+        let review = ReviewState::from_events(initial_hash, &timeline);
+        let threads = threads_from_activity(&activity, bot, &policy.triggers);
+        trace!(logger, "Threads: {:?}", threads);
+        let interdiffs = match review.current_hash {
+            Some(ref current_hash) => review
+                .review_status
+                .iter()
+                .filter_map(|(user, status)| match *status {
+                    ReviewStatus::VoteNeedReevaluation {
+                        vote_hash: Some(ref vote_hash),
+                        ..
+                    } => Some((user, vote_hash)),
+                    _ => None,
+                })
+                .sorted()
+                .map(|(user, vote_hash)| {
+                    Interdiff::new(
+                        user,
+                        vote_hash,
+                        current_hash,
+                        &pr.destination.repository.full_name,
+                        inspector,
+                    )
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        trace!(logger, "Interdiffs: {:?}", interdiffs);
+        Ok(PullRequestState {
+            review,
+            timeline,
+            threads,
+            interdiffs,
             urls,
             pr,
-            labels: HashSet::new(),
-            current_hash: None,
+            policy: policy.clone(),
+        })
+    }
+
+    /// Verdict of the votes, unless it is blocked by holds.
+    pub fn verdict(&self) -> Verdict {
+        let verdict = self.review.verdict(&self.policy.votes);
+        let holds = self.holds();
+        match verdict {
+            Verdict::Approved { .. } | Verdict::Pending if !holds.is_empty() => Verdict::OnHold {
+                by: holds
+                    .into_iter()
+                    .map(|hold| hold.user)
+                    .sorted()
+                    .dedup()
+                    .collect(),
+            },
+            verdict => verdict,
+        }
+    }
+
+    /// Holds placed with commands, plus a hold of the author for draft and work in progress
+    /// PRs. BitBucket does not tell when a PR became a draft, the hold dates from the last
+    /// update of the PR.
+    pub fn holds(&self) -> Vec<Hold> {
+        let mut holds = self.review.holds.clone();
+        let reason = if self.pr.draft {
+            Some("draft")
+        } else if is_wip(&self.pr.title) {
+            Some("work in progress")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            holds.push(Hold {
+                user: self.pr.author.username.clone(),
+                reason: Some(reason.to_string()),
+                placed_on: self.pr.updated_on,
+            });
         }
+        holds
+    }
+
+    pub fn open_threads(&self) -> impl Iterator<Item = &Thread> {
+        self.threads.iter().filter(|thread| thread.is_open())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::NoDirectory;
+    use crate::revisions::FileChange;
+
+    /// Revisions whose hashes start with the same digit make the same change.
+    struct SameFirstDigit;
+
+    impl RevisionInspector for SameFirstDigit {
+        fn same_change(&self, old: &str, new: &str) -> bool {
+            old[..1] == new[..1]
+        }
+
+        fn changes(&self, _old: &str, _new: &str) -> Option<Vec<FileChange>> {
+            None
+        }
+
+        fn commit_count(&self, _old: &str, _new: &str) -> Option<usize> {
+            None
+        }
+    }
+
+    fn date(minute: u32) -> String {
+        format!("2019-01-01T00:{:02}:00Z", minute)
+    }
+
+    fn pull_request(title: &str, draft: bool) -> PullRequest {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "title": title,
+            "state": "OPEN",
+            "draft": draft,
+            "links": {
+                "self": { "href": "https://api.bitbucket.org/2.0/repositories/o/r/pullrequests/1" },
+                "activity": {
+                    "href": "https://api.bitbucket.org/2.0/repositories/o/r/pullrequests/1/activity"
+                },
+            },
+            "author": { "username": "author" },
+            "source": { "branch": { "name": "fix" }, "commit": { "hash": "1111aaaa" } },
+            "destination": {
+                "branch": { "name": "master" },
+                "commit": null,
+                "repository": { "full_name": "o/r" },
+            },
+            "created_on": date(0),
+            "updated_on": date(0),
+        }))
+        .unwrap()
+    }
+
+    /// Comment posted at minute `id`.
+    fn reply(id: u32, parent: Option<u32>, user: &str, raw: &str) -> ActivityItem {
+        serde_json::from_value(serde_json::json!({
+            "comment": {
+                "id": id,
+                "parent": parent.map(|id| serde_json::json!({ "id": id })),
+                "content": { "raw": raw },
+                "user": { "username": user },
+                "created_on": date(id),
+                "updated_on": null,
+                "inline": null,
+                "resolution": null,
+            }
+        }))
+        .unwrap()
+    }
+
+    fn comment(id: u32, user: &str, raw: &str) -> ActivityItem {
+        reply(id, None, user, raw)
+    }
+
+    fn push(minute: u32, hash: &str) -> ActivityItem {
+        serde_json::from_value(serde_json::json!({
+            "update": { "source": { "commit": { "hash": hash } }, "date": date(minute) }
+        }))
+        .unwrap()
+    }
+
+    fn approval(minute: u32, user: &str) -> ActivityItem {
+        serde_json::from_value(serde_json::json!({
+            "approval": { "user": { "username": user }, "date": date(minute) }
+        }))
+        .unwrap()
+    }
+
+    fn state_of(
+        pr: PullRequest,
+        policy: serde_json::Value,
+        inspector: &dyn RevisionInspector,
+        activity: Vec<ActivityItem>,
+    ) -> PullRequestState {
+        let policy: Policy = serde_json::from_value(policy).unwrap();
+        let urls = PullrequestIdURLs {
+            api_url: "https://api.bitbucket.org/2.0/repositories/o/r/pullrequests/1".to_string(),
+            web_url: "https://bitbucket.org/o/r/pull-requests/1".to_string(),
+        };
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        PullRequestState::from_activity(
+            pr,
+            activity,
+            urls,
+            "bot",
+            &policy,
+            &NoDirectory,
+            inspector,
+            &logger,
+        )
+        .unwrap()
+    }
+
+    fn state(activity: Vec<ActivityItem>) -> PullRequestState {
+        state_of(
+            pull_request("Fix things", false),
+            serde_json::json!({}),
+            &SameFirstDigit,
+            activity,
+        )
+    }
+
+    fn status<'a>(state: &'a PullRequestState, user: &str) -> &'a ReviewStatus {
+        &state.review.review_status[user]
+    }
+
+    fn approved(by: &[&str]) -> Verdict {
+        Verdict::Approved {
+            by: by.iter().map(|user| user.to_string()).collect(),
+        }
+    }
+
+    fn on_hold(by: &[&str]) -> Verdict {
+        Verdict::OnHold {
+            by: by.iter().map(|user| user.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn max_votes_approve_and_min_votes_veto() {
+        let pr_state = state(vec![comment(1, "alice", "!g +1")]);
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "carol", "!g +2"),
+        ]);
+        assert_eq!(pr_state.verdict(), approved(&["alice", "carol"]));
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "bob", "!g -2 because it breaks the build"),
+        ]);
+        assert_eq!(
+            pr_state.verdict(),
+            Verdict::Vetoed {
+                by: vec!["bob".to_string()]
+            }
+        );
+        assert_eq!(
+            status(&pr_state, "bob").rationale(),
+            Some("it breaks the build")
+        );
+    }
+
+    #[test]
+    fn approvals_count_as_configured_vote() {
+        let pr_state = state(vec![approval(1, "alice")]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::Voted { vote: 1, .. }
+        ));
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
+
+        let pr_state = state_of(
+            pull_request("Fix things", false),
+            serde_json::json!({ "votes": { "approval": 2 } }),
+            &SameFirstDigit,
+            vec![approval(1, "alice")],
+        );
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn later_votes_replace_earlier_ones() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g -2"),
+            comment(2, "alice", "!g +2"),
+        ]);
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn scoped_labels_replace_each_other() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +wip +priority:low"),
+            comment(2, "bob", "!g +priority:high"),
+        ]);
+        let labels: Vec<&str> = pr_state
+            .review
+            .labels
+            .iter()
+            .map(String::as_str)
+            .sorted()
+            .collect();
+        assert_eq!(labels, vec!["priority:high", "wip"]);
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +wip +priority:low"),
+            comment(2, "bob", "!g -priority"),
+        ]);
+        assert_eq!(
+            pr_state.review.labels,
+            vec!["wip".to_string()].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn rfc_is_answered_in_its_thread() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g rfc bob"),
+            comment(2, "bob", "Elsewhere"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::RFC {
+                thread: Some(1),
+                ..
+            }
+        ));
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g rfc bob, carol"),
+            reply(2, Some(1), "bob", "Looks fine"),
+        ]);
+        match *status(&pr_state, "alice") {
+            ReviewStatus::RFC { ref requests, .. } => {
+                assert_eq!(requests[0].answer.as_ref().unwrap().comment_id, 2);
+                assert!(requests[1].answer.is_none());
+            }
+            ref status => panic!("unexpected status {:?}", status),
+        }
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g rfc bob"),
+            reply(2, Some(1), "alice", "Ping"),
+            reply(3, Some(2), "bob", "Looks fine"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::RFCAnswered { .. }
+        ));
+    }
+
+    #[test]
+    fn rfc_answered_anywhere() {
+        let pr_state = state_of(
+            pull_request("Fix things", false),
+            serde_json::json!({ "rfc_answers": "anywhere" }),
+            &SameFirstDigit,
+            vec![
+                comment(1, "alice", "!g rfc bob"),
+                comment(2, "bob", "Elsewhere"),
+            ],
+        );
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::RFCAnswered { .. }
+        ));
+    }
+
+    #[test]
+    fn holds_block_approval() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "bob", "!g hold tests are flaky"),
+        ]);
+        assert_eq!(pr_state.verdict(), on_hold(&["bob"]));
+        assert_eq!(
+            pr_state.review.holds[0].reason.as_deref(),
+            Some("tests are flaky")
+        );
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "bob", "!g hold"),
+            comment(3, "bob", "!g unhold"),
+        ]);
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn vetoes_take_precedence_over_holds() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g -2"),
+            comment(2, "bob", "!g hold"),
+        ]);
+        assert_eq!(
+            pr_state.verdict(),
+            Verdict::Vetoed {
+                by: vec!["alice".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn lifting_holds_of_others_needs_override() {
+        let activity = || {
+            vec![
+                comment(1, "alice", "!g +2"),
+                comment(2, "bob", "!g hold"),
+                comment(3, "carol", "!g hold"),
+                comment(4, "lead", "!g unhold bob"),
+            ]
+        };
+        let pr_state = state(activity());
+        assert_eq!(pr_state.verdict(), on_hold(&["bob", "carol"]));
+        assert_eq!(pr_state.review.diagnostics.len(), 1);
+
+        let policy = serde_json::json!({ "acl": { "override": { "users": ["lead"] } } });
+        let pr_state = state_of(
+            pull_request("Fix things", false),
+            policy.clone(),
+            &SameFirstDigit,
+            activity(),
+        );
+        assert_eq!(pr_state.verdict(), on_hold(&["carol"]));
+
+        let mut activity = activity();
+        activity.push(comment(5, "lead", "!g unhold all"));
+        let pr_state = state_of(
+            pull_request("Fix things", false),
+            policy,
+            &SameFirstDigit,
+            activity,
+        );
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn drafts_and_work_in_progress_are_held_by_author() {
+        let activity = || vec![comment(1, "alice", "!g +2")];
+        for pr in [
+            pull_request("Fix things", true),
+            pull_request("WIP: Fix things", false),
+        ] {
+            let pr_state = state_of(pr, serde_json::json!({}), &SameFirstDigit, activity());
+            assert_eq!(pr_state.verdict(), on_hold(&["author"]));
+        }
+    }
+
+    #[test]
+    fn retract_withdraws_votes_or_rfcs() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "alice", "!g retract"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::NoReview
+        ));
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g rfc bob"),
+            comment(2, "alice", "!g retract vote"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::RFC { .. }
+        ));
+
+        let pr_state = state(vec![
+            comment(1, "alice", "!g rfc bob"),
+            comment(2, "alice", "!g retract rfc"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::NoReview
+        ));
+    }
+
+    #[test]
+    fn pushes_invalidate_votes() {
+        let pr_state = state(vec![
+            push(0, "1111aaaa"),
+            comment(1, "alice", "!g +2"),
+            comment(2, "bob", "!g -2"),
+            push(3, "2222bbbb"),
+        ]);
+        match *status(&pr_state, "alice") {
+            ReviewStatus::VoteNeedReevaluation {
+                voted: 2,
+                ref vote_hash,
+                ..
+            } => assert_eq!(vote_hash.as_deref(), Some("1111aaaa")),
+            ref status => panic!("unexpected status {:?}", status),
+        }
+        assert!(pr_state.timeline.contains(&ReviewEvent::RevisionPushed {
+            hash: "2222bbbb".to_string(),
+            rebase_of: Vec::new(),
+            invalidates: vec!["alice".to_string(), "bob".to_string()],
+            date: date(3).parse().unwrap(),
+        }));
+        // A veto holds until it is changed.
+        assert_eq!(
+            pr_state.verdict(),
+            Verdict::Vetoed {
+                by: vec!["bob".to_string()]
+            }
+        );
+        assert_eq!(pr_state.interdiffs.len(), 2);
+    }
+
+    #[test]
+    fn votes_are_kept_per_policy() {
+        let activity = || {
+            vec![
+                push(0, "1111aaaa"),
+                comment(1, "alice", "!g +2"),
+                push(2, "1111bbbb"),
+            ]
+        };
+        let pr_state = state(activity());
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
+
+        let pr = || pull_request("Fix things", false);
+        let keep_on_rebase = || serde_json::json!({ "invalidation": { "keep_on_rebase": true } });
+        let pr_state = state_of(pr(), keep_on_rebase(), &SameFirstDigit, activity());
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+
+        let mut other_change = activity();
+        other_change.push(push(3, "2222cccc"));
+        let pr_state = state_of(pr(), keep_on_rebase(), &SameFirstDigit, other_change);
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
+
+        let never = serde_json::json!({ "invalidation": { "votes": "never" } });
+        let pr_state = state_of(pr(), never, &SameFirstDigit, activity());
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn pinned_votes_hold_for_rebases_only() {
+        let pr = || pull_request("Fix things", false);
+        let keep_on_rebase = || serde_json::json!({ "invalidation": { "keep_on_rebase": true } });
+        let activity = |current: &str| {
+            vec![
+                push(0, "1111aaaa"),
+                push(1, current),
+                comment(2, "alice", "!g +2 @1111aaaa"),
+            ]
+        };
+
+        let pr_state = state_of(
+            pr(),
+            keep_on_rebase(),
+            &SameFirstDigit,
+            activity("1111bbbb"),
+        );
+        match *status(&pr_state, "alice") {
+            ReviewStatus::Voted { ref vote_hash, .. } => {
+                assert_eq!(vote_hash.as_deref(), Some("1111aaaa"))
+            }
+            ref status => panic!("unexpected status {:?}", status),
+        }
+
+        let pr_state = state_of(
+            pr(),
+            keep_on_rebase(),
+            &SameFirstDigit,
+            activity("2222bbbb"),
+        );
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::VoteNeedReevaluation { .. }
+        ));
+
+        let pr_state = state(vec![
+            push(0, "1111aaaa"),
+            push(1, "2222bbbb"),
+            comment(2, "alice", "!g +2 @2222"),
+        ]);
+        assert_eq!(pr_state.verdict(), approved(&["alice"]));
+    }
+
+    #[test]
+    fn will_revote_keeps_the_vote_for_reference() {
+        let pr_state = state(vec![
+            comment(1, "alice", "!g +2"),
+            comment(2, "alice", "!g will_revote"),
+        ]);
+        assert!(matches!(
+            *status(&pr_state, "alice"),
+            ReviewStatus::WantsToReviewAgain { voted: Some(2), .. }
+        ));
+        assert_eq!(pr_state.verdict(), Verdict::Pending);
     }
 }
@@ -3,10 +3,23 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use gatekeeper::{
-    bitbucket::{values_from_all_pages, ActivityItem, BitBucketApiBasicAuth, PullRequest},
+    bitbucket::{
+        post_comment, update_comment, update_title, values_from_all_pages, ActivityItem,
+        BitBucketApiBasicAuth, NewComment, PullRequest,
+    },
+    diagnostics::reply,
+    events::ReviewEvent,
+    permissions::PermissionResolver,
+    policy::{Config, Policy},
+    revisions::DiffInspector,
+    status::{is_status_comment, status_comment},
+    threads::Thread,
+    title::title_with_labels,
     PullRequestState, RepositoryURLs,
 };
 
+use std::collections::{BTreeMap, HashSet};
+
 use failure::Error;
 use itertools::Itertools;
 use slog::{debug, error, o, trace, Drain, FnValue};
@@ -30,6 +43,20 @@ struct Opt {
         use_delimiter = true
     )]
     repo_slugs: Vec<String>,
+    /// JSON file with the policy, see `gatekeeper::policy`.
+    #[structopt(short = "c", long = "config", env = "GATEKEEPER_CONFIG")]
+    config: Option<String>,
+    #[structopt(long = "timeline")]
+    show_timeline: bool,
+    /// Reply in the comment thread to commands that could not be applied.
+    #[structopt(long = "reply-to-invalid-commands")]
+    reply_to_invalid_commands: bool,
+    /// Keep the labels as a `[label]...` prefix of the PR title, see `gatekeeper::title`.
+    #[structopt(long = "sync-labels-to-title")]
+    sync_labels_to_title: bool,
+    /// Keep a comment with a summary of the review up to date, see `gatekeeper::status`.
+    #[structopt(long = "status-comment")]
+    status_comment: bool,
 }
 
 fn main() {
@@ -70,14 +97,39 @@ fn app(logger: &slog::Logger) -> Result<()> {
         reqwest::Client::new(),
     );
 
+    let config = match app_args.config {
+        Some(ref path) => {
+            debug!(logger, "Loading config from {}", path);
+            Config::from_file(path)?
+        }
+        None => Config::default(),
+    };
+    trace!(logger, "Config: {:?}", config);
+
+    let permissions = PermissionResolver::new(&app_args.repo_owner, &client, logger);
+
     debug!(logger, "Repositories to process: {:?}", app_args.repo_slugs);
     for repo_slug in &app_args.repo_slugs {
-        let repo_prs = repo_prs(&app_args.repo_owner, &repo_slug, &client, &logger)?;
+        let policy = config.policy_for(repo_slug)?;
+        trace!(logger, "Policy for {}: {:?}", repo_slug, policy);
+        let repo_prs = repo_prs(
+            &app_args.repo_owner,
+            repo_slug,
+            &policy,
+            Updates {
+                reply_to_invalid_commands: app_args.reply_to_invalid_commands,
+                sync_labels_to_title: app_args.sync_labels_to_title,
+                status_comment: app_args.status_comment,
+            },
+            &client,
+            &permissions,
+            logger,
+        )?;
 
         trace!(logger, "Showing results for {}", repo_slug);
-        display_repo(&repo_slug, &logger);
+        display_repo(repo_slug, logger);
         for pr in repo_prs {
-            display_pr_results(pr, &logger);
+            display_pr_results(pr, app_args.show_timeline, logger);
         }
     }
 
@@ -90,12 +142,21 @@ fn display_repo(repo_slug: &str, _logger: &slog::Logger) {
     println!("------------------------------------------------------------------------");
 }
 
+/// Changes gatekeeper makes to pull requests besides reading them.
+#[derive(Debug, Clone, Copy)]
+struct Updates {
+    reply_to_invalid_commands: bool,
+    sync_labels_to_title: bool,
+    status_comment: bool,
+}
+
+#[allow(clippy::large_enum_variant)]
 enum PullRequestProcessing {
     Success(PullRequestState),
     Failure(PullRequest, Error),
 }
 
-fn display_pr_results(res: PullRequestProcessing, logger: &slog::Logger) {
+fn display_pr_results(res: PullRequestProcessing, show_timeline: bool, logger: &slog::Logger) {
     match res {
         PullRequestProcessing::Failure(pr, e) => {
             error!(logger, "Error processing PR {}. Err: {}", pr.id, e);
@@ -108,21 +169,98 @@ fn display_pr_results(res: PullRequestProcessing, logger: &slog::Logger) {
             println!("  PR {}: {}", pr_state.pr.id, pr_state.pr.title);
             println!("    -- author: {}", pr_state.pr.author.username);
             println!("    -- link: {}", pr_state.urls.web_url);
-            println!("    -- current_hash: {:?}", pr_state.current_hash);
-            if !pr_state.labels.is_empty() {
-                println!("    -- labels: {}", pr_state.labels.iter().join(", "));
+            println!("    -- current_hash: {:?}", pr_state.review.current_hash);
+            if !pr_state.review.labels.is_empty() {
+                println!(
+                    "    -- labels: {}",
+                    pr_state.review.labels.iter().join(", ")
+                );
             }
-            for (user, status) in &pr_state.review_status {
+            for (user, status) in &pr_state.review.review_status {
                 println!("    {}: {:?}", user, status);
+                if let Some(rationale) = status.rationale() {
+                    println!("      because {}", rationale);
+                }
+            }
+            for interdiff in &pr_state.interdiffs {
+                println!(
+                    "    -- changes since the vote of {}: {} commits, {} files: {}",
+                    interdiff.user,
+                    count(interdiff.commits),
+                    count(interdiff.files),
+                    interdiff.url
+                );
+            }
+            for hold in pr_state.holds() {
+                match hold.reason {
+                    Some(ref reason) => println!("    -- hold by {}: {}", hold.user, reason),
+                    None => println!("    -- hold by {}", hold.user),
+                }
+            }
+            println!("    -- verdict: {:?}", pr_state.verdict());
+            let mut general_threads = Vec::new();
+            let mut inline_threads: BTreeMap<&str, Vec<&Thread>> = BTreeMap::new();
+            for thread in pr_state.open_threads() {
+                match thread.inline() {
+                    Some(inline) => inline_threads
+                        .entry(inline.path.as_str())
+                        .or_default()
+                        .push(thread),
+                    None => general_threads.push(thread),
+                }
+            }
+            if !general_threads.is_empty() {
+                println!("    -- open threads: {}", general_threads.len());
+                for thread in general_threads {
+                    display_thread(thread);
+                }
+            }
+            for (path, threads) in inline_threads {
+                println!("    -- open threads on {}: {}", path, threads.len());
+                for thread in threads {
+                    display_thread(thread);
+                }
+            }
+            for diagnostic in &pr_state.review.diagnostics {
+                println!("    !! {}", diagnostic);
+            }
+            if show_timeline {
+                println!("    -- timeline:");
+                for event in &pr_state.timeline {
+                    println!("      {}: {:?}", event.date(), event);
+                }
             }
         }
     }
 }
 
+fn count(count: Option<usize>) -> String {
+    count.map_or_else(|| "?".to_string(), |count| count.to_string())
+}
+
+fn display_thread(thread: &Thread) {
+    let line = thread
+        .inline()
+        .and_then(|inline| inline.line())
+        .map(|line| format!(" at line {}", line))
+        .unwrap_or_default();
+    println!(
+        "      #{}{} by {}: {} comments, last activity {}",
+        thread.id(),
+        line,
+        thread.root.author,
+        thread.comments().len(),
+        thread.last_activity()
+    );
+}
+
 fn repo_prs(
     repo_owner: &str,
     repo_slug: &str,
+    policy: &Policy,
+    updates: Updates,
     client: &BitBucketApiBasicAuth,
+    permissions: &PermissionResolver,
     logger: &slog::Logger,
 ) -> Result<Vec<PullRequestProcessing>> {
     let logger = logger.new(o!(
@@ -134,14 +272,22 @@ fn repo_prs(
     let urls = RepositoryURLs::new(repo_owner, repo_slug);
 
     trace!(logger, "Obtaining BB/{{repo}}/pullrequests/");
-    let pullrequests = values_from_all_pages::<PullRequest>(&urls.api_url, &client, &logger)?;
+    let pullrequests = values_from_all_pages::<PullRequest>(&urls.api_url, client, &logger)?;
 
     debug!(logger, "Pull requests: {:?}", pullrequests);
     let res = pullrequests
         .into_iter()
         .map(|pr| {
-            repo_pr(pr.clone(), &urls, &client, &logger)
-                .unwrap_or_else(|e| PullRequestProcessing::Failure(pr, e))
+            repo_pr(
+                pr.clone(),
+                &urls,
+                policy,
+                updates,
+                client,
+                permissions,
+                &logger,
+            )
+            .unwrap_or_else(|e| PullRequestProcessing::Failure(pr, e))
         })
         .collect();
     Ok(res)
@@ -150,7 +296,10 @@ fn repo_prs(
 fn repo_pr(
     pr: PullRequest,
     urls: &RepositoryURLs,
+    policy: &Policy,
+    updates: Updates,
     client: &BitBucketApiBasicAuth,
+    permissions: &PermissionResolver,
     logger: &slog::Logger,
 ) -> Result<PullRequestProcessing> {
     let logger = logger.new(o!(
@@ -165,12 +314,127 @@ fn repo_pr(
     debug!(logger, "Obtaining PR activity");
     let activity = {
         let mut activity =
-            values_from_all_pages::<ActivityItem>(&pr.links.activity.href, &client, &logger)?;
+            values_from_all_pages::<ActivityItem>(&pr.links.activity.href, client, &logger)?;
         activity.reverse();
         activity
     };
     trace!(logger, "Activity: {:?}", activity);
+    let posted_status = activity.iter().rev().find_map(|item| match *item {
+        ActivityItem::Comment { ref comment }
+            if !comment.deleted && is_status_comment(comment, client.username()) =>
+        {
+            Some((comment.id, comment.content.raw.clone()))
+        }
+        _ => None,
+    });
 
-    let res = PullRequestState::from_activity(pr, activity, urls, &logger)?;
+    let inspector = DiffInspector::new(&pr, client, &logger);
+    let res = PullRequestState::from_activity(
+        pr,
+        activity,
+        urls,
+        client.username(),
+        policy,
+        permissions,
+        &inspector,
+        &logger,
+    )?;
+    if updates.reply_to_invalid_commands {
+        reply_to_diagnostics(&res, client, &logger);
+    }
+    if updates.sync_labels_to_title {
+        sync_labels_to_title(&res, client, &logger);
+    }
+    if updates.status_comment {
+        update_status_comment(&res, posted_status, client, &logger);
+    }
     Ok(PullRequestProcessing::Success(res))
 }
+
+fn sync_labels_to_title(
+    pr_state: &PullRequestState,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) {
+    let mut known: HashSet<&str> = pr_state.review.labels.iter().map(String::as_str).collect();
+    known.extend(pr_state.timeline.iter().filter_map(|event| match *event {
+        ReviewEvent::LabelAdded { ref label, .. } | ReviewEvent::LabelRemoved { ref label, .. } => {
+            Some(label.as_str())
+        }
+        _ => None,
+    }));
+    let labels = pr_state.review.labels.iter().map(String::as_str).sorted();
+    let title = title_with_labels(&pr_state.pr.title, |label| known.contains(label), labels);
+    if title == pr_state.pr.title {
+        trace!(logger, "Title is up to date");
+        return;
+    }
+    if let Err(e) = update_title(&pr_state.urls.api_url, &title, client, logger) {
+        error!(logger, "Failed to update title. Err: {}", e);
+    }
+}
+
+/// Posts the status comment, or updates `posted` (id and content of the last one posted).
+fn update_status_comment(
+    pr_state: &PullRequestState,
+    posted: Option<(u32, String)>,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) {
+    let status = status_comment(pr_state);
+    let res = match posted {
+        Some((_, ref raw)) if *raw == status => {
+            trace!(logger, "Status comment is up to date");
+            return;
+        }
+        Some((comment_id, _)) => {
+            update_comment(&pr_state.urls.api_url, comment_id, &status, client, logger)
+        }
+        None => post_comment(
+            &pr_state.urls.api_url,
+            &NewComment::new(status),
+            client,
+            logger,
+        )
+        .map(|_| ()),
+    };
+    if let Err(e) = res {
+        error!(logger, "Failed to update status comment. Err: {}", e);
+    }
+}
+
+fn reply_to_diagnostics(
+    pr_state: &PullRequestState,
+    client: &BitBucketApiBasicAuth,
+    logger: &slog::Logger,
+) {
+    let by_comment = pr_state
+        .review
+        .diagnostics
+        .iter()
+        .filter_map(|diagnostic| diagnostic.comment_id.map(|id| (id, diagnostic)))
+        .into_group_map();
+    for (comment_id, diagnostics) in by_comment.into_iter().sorted_by_key(|group| group.0) {
+        let already_replied = pr_state.timeline.iter().any(|event| match *event {
+            ReviewEvent::CommentPosted {
+                ref user,
+                parent_id,
+                ..
+            } => user == client.username() && parent_id == Some(comment_id),
+            _ => false,
+        });
+        if already_replied {
+            trace!(logger, "Already replied to comment {}", comment_id);
+            continue;
+        }
+
+        debug!(logger, "Replying to comment {}", comment_id);
+        let comment = NewComment::reply(comment_id, reply(&diagnostics, &pr_state.policy.triggers));
+        if let Err(e) = post_comment(&pr_state.urls.api_url, &comment, client, logger) {
+            error!(
+                logger,
+                "Failed to reply to comment {}. Err: {}", comment_id, e
+            );
+        }
+    }
+}
@@ -0,0 +1,125 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Extraction of plain text from Markdown comments.
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+/// A line of plain text and the 1-based line of the comment it starts at.
+#[derive(PartialEq, Debug, Clone)]
+pub struct TextLine {
+    pub line: usize,
+    pub text: String,
+}
+
+/// Plain text lines of `raw`, with Markdown escapes resolved.
+///
+/// Code blocks, blockquotes, inline code and HTML are skipped so that quoted replies and
+/// examples are not taken for what the author says.
+pub fn text_lines(raw: &str) -> Vec<TextLine> {
+    let mut lines = Vec::new();
+    let mut current: Option<TextLine> = None;
+    let mut skipped_depth = 0;
+
+    for (event, range) in Parser::new(raw).into_offset_iter() {
+        match event {
+            Event::Start(ref tag) | Event::End(ref tag) if is_block(tag) => {
+                lines.extend(current.take());
+                if is_skipped(tag) {
+                    if let Event::Start(_) = event {
+                        skipped_depth += 1;
+                    } else {
+                        skipped_depth -= 1;
+                    }
+                }
+            }
+            _ if skipped_depth > 0 => {}
+            Event::Text(text) => current
+                .get_or_insert_with(|| TextLine {
+                    line: line_of(raw, range.start),
+                    text: String::new(),
+                })
+                .text
+                .push_str(&text),
+            Event::SoftBreak | Event::HardBreak => lines.extend(current.take()),
+            _ => {}
+        }
+    }
+    lines.extend(current);
+
+    lines
+}
+
+fn is_block(tag: &Tag) -> bool {
+    match *tag {
+        Tag::Paragraph
+        | Tag::Heading(..)
+        | Tag::BlockQuote
+        | Tag::CodeBlock(_)
+        | Tag::List(_)
+        | Tag::Item
+        | Tag::FootnoteDefinition(_)
+        | Tag::Table(_)
+        | Tag::TableHead
+        | Tag::TableRow
+        | Tag::TableCell => true,
+        Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..) | Tag::Image(..) => false,
+    }
+}
+
+fn is_skipped(tag: &Tag) -> bool {
+    matches!(*tag, Tag::BlockQuote | Tag::CodeBlock(_))
+}
+
+fn line_of(raw: &str, offset: usize) -> usize {
+    raw[..offset].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(raw: &str) -> Vec<(usize, String)> {
+        text_lines(raw)
+            .into_iter()
+            .map(|line| (line.line, line.text))
+            .collect()
+    }
+
+    #[test]
+    fn paragraphs_and_breaks() {
+        assert_eq!(
+            texts("first\nsecond\n\nthird"),
+            vec![
+                (1, "first".to_string()),
+                (2, "second".to_string()),
+                (4, "third".to_string())
+            ]
+        );
+        assert!(texts("").is_empty());
+    }
+
+    #[test]
+    fn inline_formatting_is_flattened() {
+        assert_eq!(
+            texts("!g **+1** [link](http://example.com) \\+wip"),
+            vec![(1, "!g +1 link +wip".to_string())]
+        );
+        assert_eq!(texts("!g `+1` ok"), vec![(1, "!g  ok".to_string())]);
+    }
+
+    #[test]
+    fn quotes_and_code_are_skipped() {
+        let raw = "> !g -1\n\n```\n!g -2\n```\n\n    !g -3\n\nafter";
+        assert_eq!(texts(raw), vec![(9, "after".to_string())]);
+    }
+
+    #[test]
+    fn list_items_are_lines() {
+        assert_eq!(
+            texts("* one\n* two"),
+            vec![(1, "one".to_string()), (2, "two".to_string())]
+        );
+    }
+}
@@ -0,0 +1,201 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Repository permissions and group memberships from BitBucket, cached for the whole run.
+//!
+//! Lookups that fail are logged and treated as "no permission", also for the rest of the run.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use slog::{debug, error, o, trace};
+
+use crate::acl::{Directory, Permission};
+use crate::bitbucket::{
+    repository_group_permissions, repository_user_permissions, workspace_groups,
+    workspace_memberships, BitBucketApiBasicAuth, WorkspacePermission,
+};
+
+/// Known set of users, `None` if it could not be obtained.
+type Users = Option<HashSet<String>>;
+
+/// Members of each group by group slug, `None` if they could not be obtained.
+type Groups = Option<HashMap<String, HashSet<String>>>;
+
+#[derive(Debug)]
+struct RepositoryPermissions {
+    users: HashMap<String, Permission>,
+    groups: Vec<(String, Permission)>,
+}
+
+pub struct PermissionResolver<'a> {
+    workspace: String,
+    client: &'a BitBucketApiBasicAuth,
+    logger: slog::Logger,
+    repositories: RefCell<HashMap<String, Option<RepositoryPermissions>>>,
+    workspace_owners: RefCell<HashMap<String, Users>>,
+    groups: RefCell<HashMap<String, Groups>>,
+}
+
+impl<'a> PermissionResolver<'a> {
+    /// Resolver for repositories of `workspace`; groups without a workspace are looked up
+    /// there too.
+    pub fn new(
+        workspace: &str,
+        client: &'a BitBucketApiBasicAuth,
+        logger: &slog::Logger,
+    ) -> PermissionResolver<'a> {
+        PermissionResolver {
+            workspace: workspace.to_string(),
+            client,
+            logger: logger.new(o!("workspace" => workspace.to_string())),
+            repositories: RefCell::new(HashMap::new()),
+            workspace_owners: RefCell::new(HashMap::new()),
+            groups: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Strongest permission `user` has on `repo` (`workspace/slug`), if any.
+    pub fn permission(&self, user: &str, repo: &str) -> Option<Permission> {
+        let (workspace, repo_slug) = match repo.find('/') {
+            Some(idx) => (&repo[..idx], &repo[idx + 1..]),
+            None => (self.workspace.as_str(), repo),
+        };
+
+        if self.is_workspace_owner(user, workspace) {
+            return Some(Permission::Admin);
+        }
+
+        let (direct, groups) = {
+            let mut repositories = self.repositories.borrow_mut();
+            let permissions = repositories
+                .entry(repo.to_string())
+                .or_insert_with(|| self.fetch_repository(workspace, repo_slug))
+                .as_ref()?;
+            (
+                permissions.users.get(user).cloned(),
+                permissions.groups.clone(),
+            )
+        };
+        if direct == Some(Permission::Admin) {
+            return direct;
+        }
+
+        groups
+            .into_iter()
+            .filter(|(group, _)| self.is_group_member(user, workspace, group))
+            .map(|(_, permission)| permission)
+            .chain(direct)
+            .max()
+    }
+
+    pub fn is_group_member(&self, user: &str, workspace: &str, group: &str) -> bool {
+        self.group_members(workspace, group)
+            .is_some_and(|members| members.contains(user))
+    }
+
+    /// Members of `group` of `workspace`, `None` if there is no such group.
+    pub fn group_members(&self, workspace: &str, group: &str) -> Option<HashSet<String>> {
+        let mut groups = self.groups.borrow_mut();
+        let groups = groups.entry(workspace.to_string()).or_insert_with(|| {
+            debug!(self.logger, "Obtaining groups of {}", workspace);
+            match workspace_groups(workspace, self.client, &self.logger) {
+                Ok(groups) => Some(
+                    groups
+                        .into_iter()
+                        .map(|group| {
+                            let members = group.members.into_iter().map(|user| user.username);
+                            (group.slug, members.collect())
+                        })
+                        .collect(),
+                ),
+                Err(e) => {
+                    error!(
+                        self.logger,
+                        "Failed to obtain groups of {}. Err: {}", workspace, e
+                    );
+                    None
+                }
+            }
+        });
+        groups.as_ref()?.get(group).cloned()
+    }
+
+    fn is_workspace_owner(&self, user: &str, workspace: &str) -> bool {
+        let mut workspace_owners = self.workspace_owners.borrow_mut();
+        let owners = workspace_owners
+            .entry(workspace.to_string())
+            .or_insert_with(|| {
+                debug!(self.logger, "Obtaining memberships of {}", workspace);
+                match workspace_memberships(workspace, self.client, &self.logger) {
+                    Ok(memberships) => Some(
+                        memberships
+                            .into_iter()
+                            .filter(|membership| {
+                                membership.permission == WorkspacePermission::Owner
+                            })
+                            .map(|membership| membership.user.username)
+                            .collect(),
+                    ),
+                    Err(e) => {
+                        error!(
+                            self.logger,
+                            "Failed to obtain memberships of {}. Err: {}", workspace, e
+                        );
+                        None
+                    }
+                }
+            });
+        owners.as_ref().is_some_and(|owners| owners.contains(user))
+    }
+
+    fn fetch_repository(&self, workspace: &str, repo_slug: &str) -> Option<RepositoryPermissions> {
+        debug!(
+            self.logger,
+            "Obtaining permissions of {}/{}", workspace, repo_slug
+        );
+        let users = repository_user_permissions(workspace, repo_slug, self.client, &self.logger);
+        let groups = repository_group_permissions(workspace, repo_slug, self.client, &self.logger);
+        match (users, groups) {
+            (Ok(users), Ok(groups)) => {
+                let permissions = RepositoryPermissions {
+                    users: users
+                        .into_iter()
+                        .map(|entry| (entry.user.username, entry.permission))
+                        .collect(),
+                    groups: groups
+                        .into_iter()
+                        .map(|entry| (entry.group.slug, entry.permission))
+                        .collect(),
+                };
+                trace!(self.logger, "Permissions: {:?}", permissions);
+                Some(permissions)
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                error!(
+                    self.logger,
+                    "Failed to obtain permissions of {}/{}. Err: {}", workspace, repo_slug, e
+                );
+                None
+            }
+        }
+    }
+}
+
+impl<'a> Directory for PermissionResolver<'a> {
+    fn is_member(&self, user: &str, group: &str) -> bool {
+        self.is_group_member(user, &self.workspace, group)
+    }
+
+    fn can(&self, user: &str, permission: Permission, repo: &str) -> bool {
+        self.permission(user, repo)
+            .is_some_and(|granted| granted >= permission)
+    }
+
+    fn members(&self, group: &str) -> Option<Vec<String>> {
+        self.group_members(&self.workspace, group)
+            .map(|members| members.into_iter().sorted().collect())
+    }
+}
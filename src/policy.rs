@@ -0,0 +1,216 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Configuration of how gatekeeper interprets pull request activity.
+//!
+//! The config file is JSON. `default` holds the policy for every repository, `repositories`
+//! holds per-repository overrides which are merged over `default` key by key:
+//!
+//! ```json
+//! {
+//!     "default": { "edited_commands": "reject" },
+//!     "repositories": { "legacy-repo": { "edited_commands": "apply_at_edit_time" } }
+//! }
+//! ```
+//!
+//! `groups` defines teams by listing their members. They can be used wherever BitBucket groups
+//! can, e.g. in ACL rules or as `rfc` targets, and take precedence over BitBucket groups of the
+//! same name.
+
+use std::collections::HashMap;
+
+use failure::Error;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::acl::{Acl, Directory};
+use crate::command::{label_scope, Triggers};
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub default: Value,
+    #[serde(default)]
+    pub repositories: HashMap<String, Value>,
+}
+
+impl Config {
+    pub fn from_file(path: &str) -> Result<Config, Error> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    /// Policy for `repo_slug`: `default` with the repository overrides applied.
+    pub fn policy_for(&self, repo_slug: &str) -> Result<Policy, Error> {
+        let mut policy = self.default.clone();
+        if let Some(overrides) = self.repositories.get(repo_slug) {
+            merge(&mut policy, overrides);
+        }
+        if policy.is_null() {
+            return Ok(Policy::default());
+        }
+        Ok(serde_json::from_value(policy)?)
+    }
+}
+
+fn merge(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    pub triggers: Triggers,
+    pub edited_commands: EditedCommands,
+    pub inline_commands: InlineCommands,
+    pub rfc_answers: RfcAnswers,
+    pub votes: VoteScale,
+    pub invalidation: Invalidation,
+    /// Whether votes and approvals of the PR author count.
+    pub allow_self_votes: bool,
+    pub groups: HashMap<String, Vec<String>>,
+    /// Owners of paths: users or groups per path prefix, `*` for every path.
+    pub owners: HashMap<String, Vec<String>>,
+    /// Labels that may be added, any if not set. `key:*` allows any value of a scoped label.
+    pub allowed_labels: Option<Vec<String>>,
+    pub acl: Acl,
+}
+
+impl Policy {
+    /// Whether `user` owns `path`, directly or through one of `groups`.
+    pub fn owns(&self, user: &str, path: &str, directory: &dyn Directory) -> bool {
+        self.owners
+            .iter()
+            .filter(|(prefix, _)| *prefix == "*" || path.starts_with(prefix.as_str()))
+            .flat_map(|(_, owners)| owners)
+            .any(|owner| owner == user || directory.is_member(user, owner))
+    }
+
+    pub fn allows_label(&self, label: &str) -> bool {
+        let allowed_labels = match self.allowed_labels {
+            Some(ref allowed_labels) => allowed_labels,
+            None => return true,
+        };
+        allowed_labels.iter().any(|allowed| {
+            allowed == label
+                || label_scope(label).is_some_and(|scope| allowed.strip_suffix(":*") == Some(scope))
+        })
+    }
+}
+
+/// What to do with commands of a comment edited after it was posted.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EditedCommands {
+    /// Apply the current content as if it was posted at the time of the edit.
+    #[default]
+    ApplyAtEditTime,
+    /// Ignore the commands and report a diagnostic.
+    Reject,
+}
+
+/// What to do with commands in comments on code.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InlineCommands {
+    /// Apply them like commands of general comments.
+    #[default]
+    Apply,
+    /// Ignore the commands and report a diagnostic.
+    Reject,
+}
+
+/// Which comments of the requested users answer an RFC.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RfcAnswers {
+    /// Only replies in the thread of the comment requesting feedback.
+    #[default]
+    InThread,
+    /// Any comment on the PR.
+    Anywhere,
+}
+
+/// When votes stop counting for new revisions.
+///
+/// ```json
+/// {
+///     "keep_on_rebase": true,
+///     "votes": "owned_files_changed",
+///     "branches": { "main": "any_push", "release/*": { "lines_changed": 10 } }
+/// }
+/// ```
+#[derive(Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Invalidation {
+    /// Keep votes valid for revisions which are rebases of the revision voted on, see
+    /// `revisions`.
+    pub keep_on_rebase: bool,
+    pub votes: InvalidateVotes,
+    /// Rules per destination branch, a trailing `*` matches any suffix.
+    pub branches: HashMap<String, InvalidateVotes>,
+}
+
+impl Invalidation {
+    /// Rule for PRs into `branch`; the longest matching pattern wins.
+    pub fn rule_for(&self, branch: &str) -> InvalidateVotes {
+        self.branches
+            .iter()
+            .filter(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => branch.starts_with(prefix),
+                None => *pattern == branch,
+            })
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(self.votes, |(_, rule)| *rule)
+    }
+}
+
+/// Which pushes make votes need reevaluation.
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidateVotes {
+    Never,
+    #[default]
+    AnyPush,
+    /// Pushes changing files owned by the voter, see `Policy::owners`.
+    OwnedFilesChanged,
+    /// Pushes changing more than the given number of lines since the vote.
+    LinesChanged(u32),
+}
+
+/// Allowed votes, Gerrit-like: `min` vetoes the change, `max` is required for approval.
+#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct VoteScale {
+    pub min: i32,
+    pub max: i32,
+    /// Vote BitBucket's approval counts as, `1` by default like before votes were configurable.
+    pub approval: i32,
+    /// Whether votes below zero are rejected without a `because ...` rationale.
+    pub negative_needs_rationale: bool,
+}
+
+impl Default for VoteScale {
+    fn default() -> VoteScale {
+        VoteScale {
+            min: -2,
+            max: 2,
+            approval: 1,
+            negative_needs_rationale: false,
+        }
+    }
+}
+
+impl VoteScale {
+    pub fn contains(&self, vote: i32) -> bool {
+        self.min <= vote && vote <= self.max
+    }
+}
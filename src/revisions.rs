@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Comparison of pull request revisions, to tell rebases from actual changes.
+//!
+//! Two revisions make the same change when their diffs against the destination branch are
+//! equal once line numbers and blob hashes are ignored, like `git patch-id` does. A rebase
+//! with conflicts resolved by hand changes the diff and so is not the same change.
+//!
+//! Whether other pushes invalidate votes is up to `Policy::invalidation`, see
+//! `PolicyInvalidation`.
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use slog::{debug, error, o, trace};
+
+use crate::acl::Directory;
+use crate::bitbucket::{commits_between, diff, diffstat, BitBucketApiBasicAuth, PullRequest};
+use crate::policy::{InvalidateVotes, Policy};
+
+/// A file changed between two revisions.
+#[derive(PartialEq, Debug, Clone)]
+pub struct FileChange {
+    pub path: String,
+    /// Lines added plus lines removed.
+    pub lines: u32,
+}
+
+pub trait RevisionInspector {
+    /// Whether revision `new` makes the same change as revision `old`.
+    fn same_change(&self, old: &str, new: &str) -> bool;
+    /// Files changed by `new` since `old`, `None` if unknown.
+    fn changes(&self, old: &str, new: &str) -> Option<Vec<FileChange>>;
+    /// Number of commits of `new` not in `old`, `None` if unknown.
+    fn commit_count(&self, old: &str, new: &str) -> Option<usize>;
+}
+
+/// Inspector that cannot tell, every revision is a new change.
+pub struct NoInspector;
+
+impl RevisionInspector for NoInspector {
+    fn same_change(&self, _old: &str, _new: &str) -> bool {
+        false
+    }
+
+    fn changes(&self, _old: &str, _new: &str) -> Option<Vec<FileChange>> {
+        None
+    }
+
+    fn commit_count(&self, _old: &str, _new: &str) -> Option<usize> {
+        None
+    }
+}
+
+/// Old and new revision.
+type Range = (String, String);
+
+/// Inspector comparing diffs of revisions of a PR obtained from BitBucket.
+pub struct DiffInspector<'a> {
+    repository: String,
+    destination: String,
+    client: &'a BitBucketApiBasicAuth,
+    logger: slog::Logger,
+    patch_ids: RefCell<HashMap<String, Option<u64>>>,
+    changes: RefCell<HashMap<Range, Option<Vec<FileChange>>>>,
+    commit_counts: RefCell<HashMap<Range, Option<usize>>>,
+}
+
+impl<'a> DiffInspector<'a> {
+    pub fn new(
+        pr: &PullRequest,
+        client: &'a BitBucketApiBasicAuth,
+        logger: &slog::Logger,
+    ) -> DiffInspector<'a> {
+        let destination = match pr.destination.commit {
+            Some(ref commit) => commit.hash.clone(),
+            None => pr.destination.branch.name.clone(),
+        };
+        DiffInspector {
+            logger: logger.new(o!("destination" => destination.clone())),
+            repository: pr.destination.repository.full_name.clone(),
+            destination,
+            client,
+            patch_ids: RefCell::new(HashMap::new()),
+            changes: RefCell::new(HashMap::new()),
+            commit_counts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Patch id of the diff of `revision` against the destination, `None` if the diff could
+    /// not be obtained.
+    fn patch_id(&self, revision: &str) -> Option<u64> {
+        let mut patch_ids = self.patch_ids.borrow_mut();
+        *patch_ids.entry(revision.to_string()).or_insert_with(|| {
+            debug!(self.logger, "Obtaining diff of {}", revision);
+            let spec = format!("{}..{}", revision, self.destination);
+            match diff(&self.repository, &spec, self.client, &self.logger) {
+                Ok(diff) => Some(patch_id(&diff)),
+                Err(e) => {
+                    error!(
+                        self.logger,
+                        "Failed to obtain diff of {}. Err: {}", revision, e
+                    );
+                    None
+                }
+            }
+        })
+    }
+}
+
+impl<'a> RevisionInspector for DiffInspector<'a> {
+    fn same_change(&self, old: &str, new: &str) -> bool {
+        let same = match (self.patch_id(old), self.patch_id(new)) {
+            (Some(old), Some(new)) => old == new,
+            _ => false,
+        };
+        trace!(self.logger, "Same change of {} and {}: {}", old, new, same);
+        same
+    }
+
+    fn changes(&self, old: &str, new: &str) -> Option<Vec<FileChange>> {
+        let mut changes = self.changes.borrow_mut();
+        changes
+            .entry((old.to_string(), new.to_string()))
+            .or_insert_with(|| {
+                debug!(self.logger, "Obtaining changes from {} to {}", old, new);
+                let spec = format!("{}..{}", new, old);
+                match diffstat(&self.repository, &spec, self.client, &self.logger) {
+                    Ok(stats) => Some(
+                        stats
+                            .into_iter()
+                            .filter_map(|stat| {
+                                let lines = stat.lines_added + stat.lines_removed;
+                                let file = stat.new.or(stat.old)?;
+                                Some(FileChange {
+                                    path: file.path,
+                                    lines,
+                                })
+                            })
+                            .collect(),
+                    ),
+                    Err(e) => {
+                        error!(
+                            self.logger,
+                            "Failed to obtain changes from {} to {}. Err: {}", old, new, e
+                        );
+                        None
+                    }
+                }
+            })
+            .clone()
+    }
+
+    fn commit_count(&self, old: &str, new: &str) -> Option<usize> {
+        let mut commit_counts = self.commit_counts.borrow_mut();
+        *commit_counts
+            .entry((old.to_string(), new.to_string()))
+            .or_insert_with(|| {
+                debug!(self.logger, "Obtaining commits from {} to {}", old, new);
+                match commits_between(&self.repository, new, old, self.client, &self.logger) {
+                    Ok(commits) => Some(commits.len()),
+                    Err(e) => {
+                        error!(
+                            self.logger,
+                            "Failed to obtain commits from {} to {}. Err: {}", old, new, e
+                        );
+                        None
+                    }
+                }
+            })
+    }
+}
+
+/// Where to look for what changed since a vote.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Interdiff {
+    pub user: String,
+    pub from: String,
+    pub to: String,
+    /// BitBucket page comparing `from` and `to`.
+    pub url: String,
+    pub commits: Option<usize>,
+    pub files: Option<usize>,
+}
+
+impl Interdiff {
+    /// Interdiff of `user`'s vote on `from`, `to` being the current revision of a PR into
+    /// `repository` (`owner/slug`).
+    pub fn new(
+        user: &str,
+        from: &str,
+        to: &str,
+        repository: &str,
+        inspector: &dyn RevisionInspector,
+    ) -> Interdiff {
+        Interdiff {
+            user: user.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            url: format!(
+                "https://bitbucket.org/{}/branches/compare/{}%0D{}#diff",
+                repository, to, from
+            ),
+            commits: inspector.commit_count(from, to),
+            files: inspector.changes(from, to).map(|changes| changes.len()),
+        }
+    }
+}
+
+/// Decides which votes need reevaluation after a push.
+pub trait VoteInvalidation {
+    /// Whether the vote of `voter` on `voted` (`None` if unknown) needs reevaluation once
+    /// `current` is pushed.
+    fn invalidates(&self, voter: &str, voted: Option<&str>, current: &str) -> bool;
+}
+
+/// Invalidation following `rule`; votes are invalidated whenever the changes are unknown.
+pub struct PolicyInvalidation<'a> {
+    pub rule: InvalidateVotes,
+    pub policy: &'a Policy,
+    pub directory: &'a dyn Directory,
+    pub inspector: &'a dyn RevisionInspector,
+}
+
+impl<'a> VoteInvalidation for PolicyInvalidation<'a> {
+    fn invalidates(&self, voter: &str, voted: Option<&str>, current: &str) -> bool {
+        let changes = || voted.and_then(|voted| self.inspector.changes(voted, current));
+        match self.rule {
+            InvalidateVotes::Never => false,
+            InvalidateVotes::AnyPush => true,
+            InvalidateVotes::OwnedFilesChanged => changes().is_none_or(|changes| {
+                changes
+                    .iter()
+                    .any(|change| self.policy.owns(voter, &change.path, self.directory))
+            }),
+            InvalidateVotes::LinesChanged(max) => changes()
+                .is_none_or(|changes| changes.iter().map(|change| change.lines).sum::<u32>() > max),
+        }
+    }
+}
+
+/// Hash of a unified diff, ignoring line numbers and blob hashes.
+pub fn patch_id(diff: &str) -> u64 {
+    lazy_static! {
+        static ref RE_HUNK: Regex = Regex::new(r"^@@ [^@]* @@").unwrap();
+    }
+    let mut hasher = DefaultHasher::new();
+    for line in diff.lines() {
+        if line.starts_with("index ") {
+            continue;
+        }
+        RE_HUNK.replace(line, "@@").hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/src/lib.rs b/src/lib.rs
+index 3b18e51..a2c4f1d 100644
+--- a/src/lib.rs
++++ b/src/lib.rs
+@@ -10,7 +10,7 @@ fn main() {
+     let a = 1;
+-    let b = 2;
++    let b = 3;
+     let c = 4;
+";
+
+    #[test]
+    fn patch_id_ignores_line_numbers_and_blob_hashes() {
+        let moved = DIFF
+            .replace("index 3b18e51..a2c4f1d", "index 0000000..1111111")
+            .replace("@@ -10,7 +10,7 @@", "@@ -42,7 +44,7 @@");
+        assert_eq!(patch_id(DIFF), patch_id(&moved));
+    }
+
+    #[test]
+    fn patch_id_depends_on_changes() {
+        let changed = DIFF.replace("let b = 3;", "let b = 5;");
+        assert_ne!(patch_id(DIFF), patch_id(&changed));
+        let other_file = DIFF.replace("src/lib.rs", "src/main.rs");
+        assert_ne!(patch_id(DIFF), patch_id(&other_file));
+    }
+}
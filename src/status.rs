@@ -0,0 +1,131 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Summary of the review kept in a single comment on the pull request.
+//!
+//! The comment is recognised by its author, the account gatekeeper posts as, and its heading.
+//! Such comments are neither looked for commands nor counted as discussions. The summary holds no dates, so it only changes
+//! when the review does.
+
+use itertools::Itertools;
+
+use crate::bitbucket::Comment;
+use crate::revisions::Interdiff;
+use crate::{PullRequestState, ReviewStatus, RfcRequest, Verdict};
+
+pub const STATUS_HEADING: &str = "#### Review status";
+
+/// Whether `comment` is a status comment posted by `bot`.
+pub fn is_status_comment(comment: &Comment, bot: &str) -> bool {
+    comment.user.username == bot && comment.content.raw.starts_with(STATUS_HEADING)
+}
+
+/// Markdown of the status comment of `pr_state`.
+pub fn status_comment(pr_state: &PullRequestState) -> String {
+    let mut lines = vec![STATUS_HEADING.to_string(), String::new()];
+    for (user, status) in pr_state.review.review_status.iter().sorted_by_key(|s| s.0) {
+        if let Some(status) = describe(status) {
+            let interdiff = pr_state
+                .interdiffs
+                .iter()
+                .find(|interdiff| interdiff.user == *user)
+                .map(changes)
+                .unwrap_or_default();
+            lines.push(format!("* **{}**: {}{}", user, status, interdiff));
+        }
+    }
+    for hold in pr_state.holds() {
+        match hold.reason {
+            Some(ref reason) => lines.push(format!("* hold by **{}**: {}", hold.user, reason)),
+            None => lines.push(format!("* hold by **{}**", hold.user)),
+        }
+    }
+    if lines.len() > 2 {
+        lines.push(String::new());
+    }
+    lines.push(format!("Verdict: {}", verdict(&pr_state.verdict())));
+    lines.join("\n")
+}
+
+fn describe(status: &ReviewStatus) -> Option<String> {
+    let (description, rationale) = match *status {
+        ReviewStatus::NoReview => return None,
+        ReviewStatus::Voted {
+            vote,
+            ref vote_hash,
+            ref rationale,
+            ..
+        } => (format!("{}{}", signed(vote), on(vote_hash)), rationale),
+        ReviewStatus::VoteNeedReevaluation {
+            voted,
+            ref vote_hash,
+            ref rationale,
+            ..
+        } => (
+            format!("{}{}, needs reevaluation", signed(voted), on(vote_hash)),
+            rationale,
+        ),
+        ReviewStatus::WantsToReviewAgain { .. } => ("will review again".to_string(), &None),
+        ReviewStatus::RFC { ref requests, .. } => (
+            format!("asked for feedback from {}", targets(requests)),
+            &None,
+        ),
+        ReviewStatus::RFCAnswered { ref requests, .. } => {
+            (format!("got feedback from {}", targets(requests)), &None)
+        }
+    };
+    Some(match *rationale {
+        Some(ref rationale) => format!("{}, because {}", description, rationale),
+        None => description,
+    })
+}
+
+fn changes(interdiff: &Interdiff) -> String {
+    let counts = [(interdiff.commits, "commits"), (interdiff.files, "files")]
+        .iter()
+        .filter_map(|&(count, what)| count.map(|count| format!("{} {}", count, what)))
+        .join(", ");
+    if counts.is_empty() {
+        format!(" ([changes since the vote]({}))", interdiff.url)
+    } else {
+        format!(" ([changes since the vote]({}): {})", interdiff.url, counts)
+    }
+}
+
+fn signed(vote: i32) -> String {
+    if vote > 0 {
+        format!("+{}", vote)
+    } else {
+        vote.to_string()
+    }
+}
+
+fn on(vote_hash: &Option<String>) -> String {
+    match *vote_hash {
+        Some(ref hash) => format!(" on `{}`", &hash[..hash.len().min(7)]),
+        None => String::new(),
+    }
+}
+
+fn targets(requests: &[RfcRequest]) -> String {
+    requests
+        .iter()
+        .map(|request| match request.answer {
+            Some(ref answer) if answer.user != request.target.name => {
+                format!("{} (answered by {})", request.target.name, answer.user)
+            }
+            Some(_) => format!("{} (answered)", request.target.name),
+            None => request.target.name.clone(),
+        })
+        .join(", ")
+}
+
+fn verdict(verdict: &Verdict) -> String {
+    match *verdict {
+        Verdict::Approved { ref by } => format!("approved by {}", by.join(", ")),
+        Verdict::Vetoed { ref by } => format!("vetoed by {}", by.join(", ")),
+        Verdict::OnHold { ref by } => format!("on hold by {}", by.join(", ")),
+        Verdict::Pending => "pending".to_string(),
+    }
+}
@@ -0,0 +1,242 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Discussions of a pull request: comments of the activity arranged into threads.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use itertools::Itertools;
+
+use crate::bitbucket::{ActivityItem, Comment, Inline, Resolution};
+use crate::command::{is_commands_only, Triggers};
+use crate::status::is_status_comment;
+
+/// A comment with the replies to it, oldest first.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ThreadComment {
+    pub id: u32,
+    pub author: String,
+    pub created_on: DateTime<Utc>,
+    pub edited_on: Option<DateTime<Utc>>,
+    /// Deleted comments are kept so that their replies stay in place.
+    pub deleted: bool,
+    pub inline: Option<Inline>,
+    pub resolution: Option<Resolution>,
+    /// The comment consists of commands only, see `command::is_commands_only`.
+    pub commands_only: bool,
+    /// Posted by gatekeeper itself, e.g. a reply to an invalid command.
+    pub by_bot: bool,
+    pub replies: Vec<ThreadComment>,
+}
+
+impl ThreadComment {
+    fn new(comment: &Comment, bot: &str, triggers: &Triggers) -> ThreadComment {
+        ThreadComment {
+            id: comment.id,
+            author: comment.user.username.clone(),
+            created_on: comment.created_on,
+            edited_on: comment.edited_on(),
+            deleted: comment.deleted,
+            inline: comment.inline.clone(),
+            resolution: comment.resolution.clone(),
+            commands_only: is_commands_only(&comment.content.raw, triggers),
+            by_bot: comment.user.username == bot,
+            replies: Vec::new(),
+        }
+    }
+
+    fn collect<'a>(&'a self, comments: &mut Vec<&'a ThreadComment>) {
+        comments.push(self);
+        for reply in &self.replies {
+            reply.collect(comments);
+        }
+    }
+}
+
+/// A top-level comment with all the replies under it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Thread {
+    pub root: ThreadComment,
+}
+
+impl Thread {
+    /// Id of the top-level comment.
+    pub fn id(&self) -> u32 {
+        self.root.id
+    }
+
+    /// Anchor in the diff, `None` for general discussions.
+    pub fn inline(&self) -> Option<&Inline> {
+        self.root.inline.as_ref()
+    }
+
+    /// BitBucket resolves whole threads, the resolution is kept on the top-level comment.
+    pub fn is_resolved(&self) -> bool {
+        self.root.resolution.is_some()
+    }
+
+    /// Unresolved and with at least one comment that was not deleted, is not just commands and
+    /// was not posted by gatekeeper, so that e.g. a lone `!g +1` is not taken for a discussion,
+    /// even with a reply from gatekeeper.
+    pub fn is_open(&self) -> bool {
+        !self.is_resolved()
+            && self
+                .comments()
+                .iter()
+                .any(|comment| !comment.deleted && !comment.commands_only && !comment.by_bot)
+    }
+
+    /// All comments of the thread, depth first.
+    pub fn comments(&self) -> Vec<&ThreadComment> {
+        let mut comments = Vec::new();
+        self.root.collect(&mut comments);
+        comments
+    }
+
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        self.comments()
+            .iter()
+            .flat_map(|comment| {
+                let resolved_on = comment.resolution.as_ref().map(|r| r.created_on);
+                vec![Some(comment.created_on), comment.edited_on, resolved_on]
+            })
+            .flatten()
+            .max()
+            .unwrap_or(self.root.created_on)
+    }
+
+    /// Authors of the comments, sorted.
+    pub fn participants(&self) -> Vec<&str> {
+        self.comments()
+            .iter()
+            .map(|comment| comment.author.as_str())
+            .sorted()
+            .dedup()
+            .collect()
+    }
+}
+
+/// Threads of the comments in `activity`, ordered by the time they were started.
+///
+/// Replies to comments missing from the activity start threads of their own. Status comments
+/// of `bot`, see `status`, are left out.
+pub fn threads_from_activity(
+    activity: &[ActivityItem],
+    bot: &str,
+    triggers: &Triggers,
+) -> Vec<Thread> {
+    let mut comments: HashMap<u32, &Comment> = HashMap::new();
+    for item in activity {
+        match *item {
+            ActivityItem::Comment { ref comment } if !is_status_comment(comment, bot) => {
+                comments.insert(comment.id, comment);
+            }
+            _ => {}
+        }
+    }
+
+    let mut replies: HashMap<u32, Vec<&Comment>> = HashMap::new();
+    let mut roots = Vec::new();
+    for comment in comments
+        .values()
+        .sorted_by_key(|comment| (comment.created_on, comment.id))
+    {
+        match comment.parent {
+            Some(ref parent) if comments.contains_key(&parent.id) => {
+                replies.entry(parent.id).or_default().push(comment)
+            }
+            _ => roots.push(comment),
+        }
+    }
+
+    roots
+        .into_iter()
+        .map(|root| Thread {
+            root: build(root, &replies, bot, triggers),
+        })
+        .collect()
+}
+
+fn build(
+    comment: &Comment,
+    replies: &HashMap<u32, Vec<&Comment>>,
+    bot: &str,
+    triggers: &Triggers,
+) -> ThreadComment {
+    let mut res = ThreadComment::new(comment, bot, triggers);
+    if let Some(comment_replies) = replies.get(&comment.id) {
+        res.replies = comment_replies
+            .iter()
+            .map(|reply| build(reply, replies, bot, triggers))
+            .collect();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn comment(id: u32, parent: Option<u32>, user: &str, raw: &str) -> ActivityItem {
+        serde_json::from_value(serde_json::json!({
+            "comment": {
+                "id": id,
+                "parent": parent.map(|id| serde_json::json!({ "id": id })),
+                "content": { "raw": raw },
+                "user": { "username": user },
+                "created_on": format!("2019-01-01T00:00:0{}Z", id),
+                "updated_on": null,
+                "inline": null,
+                "resolution": null,
+            }
+        }))
+        .unwrap()
+    }
+
+    fn open(activity: &[ActivityItem]) -> Vec<u32> {
+        threads_from_activity(activity, "bot", &Triggers::default())
+            .iter()
+            .filter(|thread| thread.is_open())
+            .map(Thread::id)
+            .collect()
+    }
+
+    #[test]
+    fn command_comments_are_not_discussions() {
+        let activity = vec![
+            comment(1, None, "alice", "!g +1"),
+            comment(2, None, "alice", "Why this way?"),
+            comment(3, None, "bob", "!g +wip\n\nNot ready, see the tests"),
+        ];
+        assert_eq!(open(&activity), vec![2, 3]);
+    }
+
+    #[test]
+    fn replies_of_the_bot_do_not_open_threads() {
+        let activity = vec![
+            comment(1, None, "alice", "!g +7"),
+            comment(
+                2,
+                Some(1),
+                "bot",
+                "Gatekeeper ignored a command from @alice",
+            ),
+            comment(3, None, "alice", "!g +1"),
+            comment(4, Some(3), "bob", "Are you sure?"),
+        ];
+        assert_eq!(open(&activity), vec![3]);
+    }
+
+    #[test]
+    fn status_comments_of_the_bot_are_left_out() {
+        let status = format!("{}\n\nVerdict: pending", crate::status::STATUS_HEADING);
+        let activity = vec![
+            comment(1, None, "bot", &status),
+            comment(2, None, "mallory", &status),
+        ];
+        let threads = threads_from_activity(&activity, "bot", &Triggers::default());
+        assert_eq!(threads.iter().map(Thread::id).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,111 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Labels reflected in the pull request title as a prefix, e.g. `[wip][urgent] Title`.
+//!
+//! BitBucket has no labels of its own. When syncing is enabled gatekeeper manages the
+//! bracketed groups of the prefix that are labels: they are replaced with the current labels
+//! on every change. Other groups, e.g. `[JIRA-123]`, are left as they are.
+
+/// Bracketed groups of the prefix and the title without it.
+pub fn parse_title(title: &str) -> (Vec<String>, &str) {
+    let (groups, rest) = prefix_groups(title);
+    let labels = groups.iter().map(|(label, _)| label.to_string()).collect();
+    (labels, rest)
+}
+
+/// `title` with the groups of its prefix for which `is_managed` holds replaced by `labels`,
+/// in the given order.
+pub fn title_with_labels<'a, I, F>(title: &str, is_managed: F, labels: I) -> String
+where
+    I: IntoIterator<Item = &'a str>,
+    F: Fn(&str) -> bool,
+{
+    let (groups, rest) = prefix_groups(title);
+    let kept: String = groups
+        .iter()
+        .filter(|(label, _)| !is_managed(label))
+        .map(|(_, raw)| *raw)
+        .collect();
+    let prefix: String = labels
+        .into_iter()
+        .map(|label| format!("[{}]", label))
+        .collect();
+    if prefix.is_empty() {
+        format!("{}{}", kept, rest)
+    } else {
+        format!("{} {}{}", prefix, kept, rest)
+    }
+}
+
+/// Whether the title marks the pull request as work in progress, by a `[wip]` label or a
+/// `WIP:` prefix.
+pub fn is_wip(title: &str) -> bool {
+    let (labels, title) = parse_title(title);
+    labels.iter().any(|label| label.eq_ignore_ascii_case("wip"))
+        || title
+            .get(..4)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("wip:"))
+}
+
+/// Bracketed groups of the prefix, each with the text it spans including the whitespace
+/// after it, and the title without the prefix.
+fn prefix_groups(title: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut groups = Vec::new();
+    let mut rest = title.trim_start();
+    while let Some(bracketed) = rest.strip_prefix('[') {
+        match bracketed.find(']') {
+            Some(end) => {
+                let next = bracketed[end + 1..].trim_start();
+                groups.push((&bracketed[..end], &rest[..rest.len() - next.len()]));
+                rest = next;
+            }
+            None => break,
+        }
+    }
+    (groups, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_prefix() {
+        assert_eq!(
+            parse_title("  [wip] [urgent]Fix thing"),
+            (vec!["wip".to_string(), "urgent".to_string()], "Fix thing")
+        );
+        assert_eq!(parse_title("Fix [thing]"), (vec![], "Fix [thing]"));
+        assert_eq!(parse_title("[unclosed Fix"), (vec![], "[unclosed Fix"));
+    }
+
+    #[test]
+    fn replaces_managed_groups_only() {
+        let managed = |label: &str| label == "wip" || label == "urgent";
+        assert_eq!(
+            title_with_labels("[JIRA-123] Fix thing", managed, vec![]),
+            "[JIRA-123] Fix thing"
+        );
+        assert_eq!(
+            title_with_labels("[wip][JIRA-123] Fix thing", managed, vec!["urgent"]),
+            "[urgent] [JIRA-123] Fix thing"
+        );
+        assert_eq!(
+            title_with_labels("[urgent] [JIRA-123] Fix thing", managed, vec!["urgent"]),
+            "[urgent] [JIRA-123] Fix thing"
+        );
+        assert_eq!(
+            title_with_labels("[wip] Fix thing", managed, vec![]),
+            "Fix thing"
+        );
+    }
+
+    #[test]
+    fn detects_wip() {
+        assert!(is_wip("[WIP] Fix thing"));
+        assert!(is_wip("[urgent] wip: Fix thing"));
+        assert!(!is_wip("Wipe caches"));
+    }
+}